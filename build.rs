@@ -1,7 +1,31 @@
 extern crate winres;
 
+use std::env;
+
+use minisign_verify::PublicKey;
+
 fn main() {
-    if cfg!(target_os = "windows") {
+    println!("cargo:rerun-if-changed=assets/logo.ico");
+    println!("cargo:rerun-if-env-changed=COLLAPSE_UPDATER_PUBLIC_KEYS");
+
+    // A key that doesn't parse would otherwise be dropped quietly, and with
+    // no keys left signatures wouldn't be checked at all
+    let keys = env::var("COLLAPSE_UPDATER_PUBLIC_KEYS").unwrap_or_default();
+    for key in keys
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|key| !key.is_empty())
+    {
+        if let Err(err) = PublicKey::from_base64(key) {
+            panic!(
+                "Invalid key in COLLAPSE_UPDATER_PUBLIC_KEYS: {}: {}",
+                key, err
+            );
+        }
+    }
+
+    // `cfg!` here would describe the machine running the build script, not
+    // the one the updater is built for
+    if env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("windows") {
         let mut res = winres::WindowsResource::new();
         res.set_icon("./assets/logo.ico");
         res.compile().unwrap();
@@ -0,0 +1,527 @@
+//! Builds shipped as a `.zip` or `.tar.gz` bundle of the loader and its
+//! resources: unpacking them safely and finding what to start inside.
+
+use std::{
+    fs::{self, File},
+    io::{self, Read},
+    path::{Component, Path, PathBuf},
+};
+
+use flate2::read::GzDecoder;
+use glob::{MatchOptions, Pattern};
+use serde::Deserialize;
+use tar::EntryType;
+use zip::ZipArchive;
+
+use crate::{install::with_suffix, platform, UpdaterError};
+
+/// Suffixes (lowercase) of release assets that are unpacked rather than run.
+pub const ARCHIVE_SUFFIXES: &[&str] = &[".zip", ".tar.gz", ".tgz"];
+
+/// File at the top of a bundle naming its entry point, e.g.
+/// `{"entry": "bin/CollapseLoader"}`.
+pub const MANIFEST_NAME: &str = "collapse_manifest.json";
+
+/// Added to the extraction directory while an archive is being unpacked.
+pub const STAGING_SUFFIX: &str = ".extracting";
+
+/// What loader executables are named, for bundles without a manifest.
+const LOADER_PATTERN: &str = "CollapseLoader*";
+
+#[derive(Deserialize)]
+struct Manifest {
+    /// Path of the executable, relative to the manifest.
+    entry: String,
+}
+
+pub fn is_archive(path: &Path) -> bool {
+    archive_suffix(path).is_some()
+}
+
+fn archive_suffix(path: &Path) -> Option<&'static str> {
+    let name = path.file_name()?.to_str()?.to_lowercase();
+    ARCHIVE_SUFFIXES
+        .iter()
+        .copied()
+        .find(|suffix| name.ends_with(suffix))
+}
+
+/// Directory an archive is unpacked into: next to it, named after it
+/// without the archive suffix.
+pub fn extract_dir(archive: &Path) -> PathBuf {
+    let name = archive
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+    let stem = archive_suffix(archive)
+        .and_then(|suffix| name.get(..name.len() - suffix.len()))
+        .unwrap_or(name);
+
+    archive.with_file_name(if stem.is_empty() { "contents" } else { stem })
+}
+
+/// Unpacks `archive` into [`extract_dir`], replacing whatever was there.
+/// The contents only appear once they are complete, and an entry that
+/// would end up outside of the directory fails the whole archive.
+pub fn extract(archive: &Path) -> Result<PathBuf, UpdaterError> {
+    let dir = extract_dir(archive);
+    let staging = with_suffix(&dir, STAGING_SUFFIX);
+    let _ = fs::remove_dir_all(&staging);
+
+    let file = File::open(archive).map_err(UpdaterError::io(format!(
+        "Failed to open {}",
+        archive.display()
+    )))?;
+    let unpacked = match archive_suffix(archive) {
+        Some(".zip") => unpack_zip(file, archive, &staging),
+        _ => unpack_tar_gz(file, archive, &staging),
+    };
+    if let Err(err) = unpacked {
+        let _ = fs::remove_dir_all(&staging);
+        return Err(err);
+    }
+
+    let _ = fs::remove_dir_all(&dir);
+    fs::rename(&staging, &dir).map_err(UpdaterError::io(format!(
+        "Failed to move {} into place",
+        dir.display()
+    )))?;
+
+    Ok(dir)
+}
+
+fn unpack_tar_gz(file: File, archive: &Path, dest: &Path) -> Result<(), UpdaterError> {
+    fs::create_dir_all(dest).map_err(unpack_failed(archive))?;
+
+    let mut tar = tar::Archive::new(GzDecoder::new(file));
+    for entry in tar.entries().map_err(unpack_failed(archive))? {
+        let mut entry = entry.map_err(unpack_failed(archive))?;
+        let name = entry.path().map_err(unpack_failed(archive))?.into_owned();
+        if enclosed(&name).is_none() {
+            return Err(unsafe_entry(archive, &name));
+        }
+
+        // Hard link targets are relative to the archive, symlink targets to
+        // the link itself
+        let link = entry.link_name().map_err(unpack_failed(archive))?;
+        let link_is_safe = match (entry.header().entry_type(), link) {
+            (EntryType::Link, Some(target)) => enclosed(&target).is_some(),
+            (EntryType::Symlink, Some(target)) => link_stays_inside(&target),
+            (EntryType::Link | EntryType::Symlink, None) => false,
+            _ => true,
+        };
+        if !link_is_safe {
+            return Err(unsafe_entry(archive, &name));
+        }
+
+        // `unpack_in` also refuses to write through symlinks leading out
+        if !entry.unpack_in(dest).map_err(unpack_failed(archive))? {
+            return Err(unsafe_entry(archive, &name));
+        }
+    }
+
+    Ok(())
+}
+
+fn unpack_zip(file: File, archive: &Path, dest: &Path) -> Result<(), UpdaterError> {
+    let invalid = |err: zip::result::ZipError| {
+        UpdaterError::InvalidResponse(format!("Failed to unpack {}: {}", archive.display(), err))
+    };
+
+    fs::create_dir_all(dest).map_err(unpack_failed(archive))?;
+
+    let mut zip = ZipArchive::new(file).map_err(invalid)?;
+    // Created last, so nothing else gets written through them
+    let mut symlinks = Vec::new();
+    for i in 0..zip.len() {
+        let mut entry = zip.by_index(i).map_err(invalid)?;
+        let name = PathBuf::from(entry.name());
+        let path = enclosed(&name).ok_or_else(|| unsafe_entry(archive, &name))?;
+        let out = dest.join(&path);
+
+        if entry.is_dir() {
+            fs::create_dir_all(&out).map_err(unpack_failed(archive))?;
+            continue;
+        }
+        if let Some(parent) = out.parent() {
+            fs::create_dir_all(parent).map_err(unpack_failed(archive))?;
+        }
+
+        if entry.is_symlink() {
+            let mut target = String::new();
+            entry
+                .read_to_string(&mut target)
+                .map_err(unpack_failed(archive))?;
+            if !link_stays_inside(Path::new(&target)) {
+                return Err(unsafe_entry(archive, &name));
+            }
+            symlinks.push((out, target));
+            continue;
+        }
+
+        let mut file = File::create(&out).map_err(unpack_failed(archive))?;
+        io::copy(&mut entry, &mut file).map_err(unpack_failed(archive))?;
+
+        #[cfg(unix)]
+        if let Some(mode) = entry.unix_mode() {
+            use std::os::unix::fs::PermissionsExt;
+
+            fs::set_permissions(&out, fs::Permissions::from_mode(mode & 0o777))
+                .map_err(unpack_failed(archive))?;
+        }
+    }
+
+    for (link, target) in symlinks {
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(target, &link).map_err(unpack_failed(archive))?;
+        // Creating symlinks needs extra privileges on Windows, and bundles
+        // for it have no reason to contain any
+        #[cfg(not(unix))]
+        let _ = (link, target);
+    }
+
+    Ok(())
+}
+
+fn unpack_failed(archive: &Path) -> impl FnOnce(io::Error) -> UpdaterError {
+    UpdaterError::io(format!("Failed to unpack {}", archive.display()))
+}
+
+fn unsafe_entry(archive: &Path, name: &Path) -> UpdaterError {
+    UpdaterError::InvalidResponse(format!(
+        "{} contains an entry outside of its directory: {}",
+        archive.display(),
+        name.display()
+    ))
+}
+
+/// `path` if it stays inside the directory it is relative to: no root,
+/// drive prefix or `..`.
+pub fn enclosed(path: &Path) -> Option<PathBuf> {
+    let mut enclosed = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => enclosed.push(part),
+            Component::CurDir => {}
+            Component::RootDir | Component::Prefix(_) | Component::ParentDir => return None,
+        }
+    }
+
+    (!enclosed.as_os_str().is_empty()).then_some(enclosed)
+}
+
+/// Whether a symlink to `target` can only lead further into the directory
+/// it is in. Counting `..` against the link's depth isn't enough: another
+/// link in the same archive can stand for any number of levels (`x -> .`
+/// makes `x/..` leave the archive), so targets with `..` are refused.
+fn link_stays_inside(target: &Path) -> bool {
+    target
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+/// The top of an unpacked bundle: `dir` itself, or the single directory
+/// inside it if the archive wraps everything in one.
+pub fn bundle_root(dir: &Path) -> PathBuf {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return dir.to_path_buf();
+    };
+    let entries: Vec<_> = entries.filter_map(|res| res.ok()).collect();
+
+    match entries.as_slice() {
+        [only] if only.file_type().is_ok_and(|ft| ft.is_dir()) => only.path(),
+        _ => dir.to_path_buf(),
+    }
+}
+
+/// The executable to start in the bundle unpacked at `root`, from `archive`:
+/// the file matching `pattern` if given, otherwise the one the bundle's
+/// manifest names, otherwise a runnable `CollapseLoader*` or the only
+/// runnable file there is. Shallower files win when several match.
+pub fn find_entry(
+    root: &Path,
+    archive: &Path,
+    pattern: Option<&str>,
+) -> Result<PathBuf, UpdaterError> {
+    let not_found = |entry: Option<&str>| UpdaterError::NoEntryPoint {
+        archive: archive.to_path_buf(),
+        entry: entry.map(str::to_string),
+    };
+
+    let mut files = files_in(root);
+    files.sort_by_key(|file| (file.components().count(), file.clone()));
+
+    if let Some(pattern) = pattern {
+        let glob = Pattern::new(pattern).map_err(|err| {
+            UpdaterError::Config(format!("Invalid entry pattern {}: {}", pattern, err))
+        })?;
+        return files
+            .into_iter()
+            .find(|file| matches(&glob, pattern, root, file))
+            .ok_or_else(|| not_found(Some(pattern)));
+    }
+
+    let manifest = root.join(MANIFEST_NAME);
+    if manifest.is_file() {
+        let text = fs::read_to_string(&manifest).map_err(UpdaterError::io(format!(
+            "Failed to read {}",
+            manifest.display()
+        )))?;
+        let Manifest { entry } = serde_json::from_str(&text).map_err(UpdaterError::json(
+            format!("Invalid {} in {}", MANIFEST_NAME, archive.display()),
+        ))?;
+
+        return enclosed(Path::new(&entry))
+            .map(|path| root.join(path))
+            .filter(|path| path.is_file())
+            .ok_or_else(|| not_found(Some(&entry)));
+    }
+
+    let glob = Pattern::new(LOADER_PATTERN).expect("the loader pattern is valid");
+    let mut runnable: Vec<PathBuf> = files
+        .into_iter()
+        .filter(|file| platform::is_runnable(file))
+        .collect();
+    match runnable
+        .iter()
+        .position(|file| matches(&glob, LOADER_PATTERN, root, file))
+    {
+        Some(index) => Ok(runnable.swap_remove(index)),
+        None if runnable.len() == 1 => Ok(runnable.remove(0)),
+        None => Err(not_found(None)),
+    }
+}
+
+/// Matches `file` against a glob: by path relative to `root` if the
+/// pattern has a `/` in it, by file name otherwise. Case doesn't matter.
+fn matches(glob: &Pattern, pattern: &str, root: &Path, file: &Path) -> bool {
+    let options = MatchOptions {
+        case_sensitive: false,
+        require_literal_separator: true,
+        ..MatchOptions::new()
+    };
+
+    if pattern.contains('/') {
+        let relative = file.strip_prefix(root).unwrap_or(file);
+        let relative: Vec<_> = relative
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect();
+        glob.matches_with(&relative.join("/"), options)
+    } else {
+        file.file_name()
+            .is_some_and(|name| glob.matches_with(&name.to_string_lossy(), options))
+    }
+}
+
+/// Every regular file under `dir`, recursively.
+pub fn files_in(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.filter_map(|res| res.ok()) {
+            match entry.file_type() {
+                Ok(ft) if ft.is_dir() => pending.push(entry.path()),
+                Ok(ft) if ft.is_file() => files.push(entry.path()),
+                _ => {}
+            }
+        }
+    }
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+    use zip::{write::SimpleFileOptions, ZipWriter};
+
+    use super::*;
+
+    /// An entry for a test archive: path, symlink target (if a link) and
+    /// contents, written as they are without any of the checks the
+    /// archive libraries do.
+    type Entry<'a> = (&'a str, Option<&'a str>, &'a [u8]);
+
+    fn tar_gz(dir: &Path, entries: &[Entry]) -> PathBuf {
+        let archive = dir.join("bundle.tar.gz");
+        let file = File::create(&archive).unwrap();
+        let mut builder = tar::Builder::new(GzEncoder::new(file, Compression::fast()));
+        for (path, link, data) in entries {
+            let mut header = tar::Header::new_gnu();
+            let gnu = header.as_gnu_mut().unwrap();
+            gnu.name[..path.len()].copy_from_slice(path.as_bytes());
+            if let Some(link) = link {
+                gnu.linkname[..link.len()].copy_from_slice(link.as_bytes());
+            }
+            header.set_entry_type(match link {
+                Some(_) => EntryType::Symlink,
+                None => EntryType::Regular,
+            });
+            header.set_size(data.len() as u64);
+            header.set_mode(0o755);
+            header.set_cksum();
+            builder.append(&header, *data).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+        archive
+    }
+
+    fn zip(dir: &Path, entries: &[Entry]) -> PathBuf {
+        let archive = dir.join("bundle.zip");
+        let mut zip = ZipWriter::new(File::create(&archive).unwrap());
+        let options = SimpleFileOptions::default().unix_permissions(0o755);
+        for (path, link, data) in entries {
+            match link {
+                Some(link) => zip.add_symlink(*path, *link, options).unwrap(),
+                None => {
+                    zip.start_file(*path, options).unwrap();
+                    zip.write_all(data).unwrap();
+                }
+            }
+        }
+        zip.finish().unwrap();
+        archive
+    }
+
+    /// Unpacks the archive made by `make` from `entries` inside a fresh
+    /// directory, returning that directory and the result.
+    fn unpack(
+        make: fn(&Path, &[Entry]) -> PathBuf,
+        entries: &[Entry],
+    ) -> (tempfile::TempDir, Result<PathBuf, UpdaterError>) {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("versions").join("v1.0");
+        fs::create_dir_all(&dir).unwrap();
+        let archive = make(&dir, entries);
+        let result = extract(&archive);
+        (root, result)
+    }
+
+    fn assert_rejected(make: fn(&Path, &[Entry]) -> PathBuf, entries: &[Entry]) {
+        let (root, result) = unpack(make, entries);
+        assert!(
+            matches!(result, Err(UpdaterError::InvalidResponse(_))),
+            "{:?} was unpacked",
+            entries.iter().map(|entry| entry.0).collect::<Vec<_>>()
+        );
+        let dir = root.path().join("versions").join("v1.0");
+        assert!(!dir.join("bundle").exists());
+        assert!(!dir.join("bundle.extracting").exists());
+        assert!(!root.path().join("versions").join("evil").exists());
+    }
+
+    #[test]
+    fn enclosed_keeps_relative_paths() {
+        assert_eq!(enclosed(Path::new("a/b")), Some(PathBuf::from("a/b")));
+        assert_eq!(enclosed(Path::new("./a/./b")), Some(PathBuf::from("a/b")));
+    }
+
+    #[test]
+    fn enclosed_refuses_paths_leading_out() {
+        assert_eq!(enclosed(Path::new("../a")), None);
+        assert_eq!(enclosed(Path::new("a/../b")), None);
+        assert_eq!(enclosed(Path::new("/etc/passwd")), None);
+        assert_eq!(enclosed(Path::new("")), None);
+        assert_eq!(enclosed(Path::new(".")), None);
+    }
+
+    #[test]
+    fn links_may_only_go_deeper() {
+        assert!(link_stays_inside(Path::new("libfoo.so.1")));
+        assert!(link_stays_inside(Path::new("./lib/libfoo.so")));
+        assert!(!link_stays_inside(Path::new("../lib/libfoo.so")));
+        assert!(!link_stays_inside(Path::new("x/..")));
+        assert!(!link_stays_inside(Path::new("/usr/lib/libfoo.so")));
+    }
+
+    #[test]
+    fn extract_dir_drops_the_archive_suffix() {
+        assert_eq!(
+            extract_dir(Path::new("v1/Loader-linux.TAR.GZ")),
+            Path::new("v1/Loader-linux")
+        );
+        assert_eq!(extract_dir(Path::new("v1/.zip")), Path::new("v1/contents"));
+    }
+
+    #[test]
+    fn rejects_entries_outside_of_the_directory() {
+        for make in [tar_gz, zip] {
+            assert_rejected(make, &[("../evil", None, b"x")]);
+            assert_rejected(make, &[("a/../../evil", None, b"x")]);
+        }
+        assert_rejected(tar_gz, &[("/tmp/evil", None, b"x")]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_symlinks_leading_out() {
+        for make in [tar_gz, zip] {
+            assert_rejected(make, &[("s", Some("../evil"), b"")]);
+            assert_rejected(make, &[("s", Some("/etc"), b"")]);
+            // Each fine on its own as far as counting levels goes
+            assert_rejected(make, &[("x", Some("."), b""), ("s", Some("x/.."), b"")]);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unpacks_files_and_links_inside() {
+        for make in [tar_gz, zip] {
+            let (_root, result) = unpack(
+                make,
+                &[
+                    ("Bundle/CollapseLoader", None, b"#!/bin/sh\n"),
+                    ("Bundle/lib/libfoo.so.1", None, b"lib"),
+                    ("Bundle/lib/libfoo.so", Some("libfoo.so.1"), b""),
+                ],
+            );
+            let dir = result.unwrap();
+            let root = bundle_root(&dir);
+            assert_eq!(root, dir.join("Bundle"));
+            assert_eq!(fs::read(root.join("lib/libfoo.so")).unwrap(), b"lib");
+            assert_eq!(
+                find_entry(&root, &dir, None).unwrap(),
+                root.join("CollapseLoader")
+            );
+        }
+    }
+
+    #[test]
+    fn manifest_names_the_entry_point() {
+        let (_root, result) = unpack(
+            zip,
+            &[
+                (MANIFEST_NAME, None, br#"{"entry": "bin/run"}"#),
+                ("bin/run", None, b"run"),
+                ("bin/CollapseLoader", None, b"loader"),
+            ],
+        );
+        let dir = result.unwrap();
+        assert_eq!(find_entry(&dir, &dir, None).unwrap(), dir.join("bin/run"));
+        assert_eq!(
+            find_entry(&dir, &dir, Some("bin/Collapse*")).unwrap(),
+            dir.join("bin/CollapseLoader")
+        );
+    }
+
+    #[test]
+    fn manifest_entry_must_stay_inside() {
+        let (_root, result) = unpack(
+            zip,
+            &[
+                (MANIFEST_NAME, None, br#"{"entry": "../bundle.zip"}"#),
+                ("CollapseLoader", None, b"loader"),
+            ],
+        );
+        let dir = result.unwrap();
+        assert!(matches!(
+            find_entry(&dir, &dir, None),
+            Err(UpdaterError::NoEntryPoint { .. })
+        ));
+    }
+}
@@ -0,0 +1,74 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+pub const CACHE_FILE_NAME: &str = "collapse_updater.cache.json";
+
+/// A previously successful API response and the validators needed to ask
+/// the server whether it has changed.
+#[derive(Serialize, Deserialize)]
+pub struct CachedResponse {
+    pub body: String,
+    #[serde(default)]
+    pub etag: Option<String>,
+    #[serde(default)]
+    pub last_modified: Option<String>,
+    /// Unix time of the last time the server confirmed this response.
+    #[serde(default)]
+    pub checked_at: u64,
+}
+
+impl CachedResponse {
+    /// Whether the response was confirmed less than `interval` seconds ago.
+    pub fn is_fresh(&self, interval: u64) -> bool {
+        unix_now().saturating_sub(self.checked_at) < interval
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or(0)
+}
+
+/// API responses from earlier runs, keyed by request URL, used when the API
+/// can't be asked again right now.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct ReleaseCache {
+    pub responses: HashMap<String, CachedResponse>,
+}
+
+impl ReleaseCache {
+    /// Loads the cache file from `root`, treating a missing or unreadable
+    /// one as empty.
+    pub fn load(root: &Path) -> Self {
+        fs::read_to_string(root.join(CACHE_FILE_NAME))
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, root: &Path) -> Result<(), io::Error> {
+        let contents = serde_json::to_string(self).map_err(io::Error::other)?;
+        fs::write(root.join(CACHE_FILE_NAME), contents)
+    }
+
+    pub fn get(&self, url: &str) -> Option<&CachedResponse> {
+        self.responses.get(url)
+    }
+
+    pub fn get_mut(&mut self, url: &str) -> Option<&mut CachedResponse> {
+        self.responses.get_mut(url)
+    }
+
+    pub fn insert(&mut self, url: &str, response: CachedResponse) {
+        self.responses.insert(url.to_string(), response);
+    }
+}
@@ -0,0 +1,326 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use glob::Pattern;
+use reqwest::Url;
+use serde::Deserialize;
+
+use crate::{
+    cli::Options,
+    install::{safe_file_name, VERSIONS_DIR},
+    launcher::LaunchMode,
+    retry::RetryPolicy,
+    UpdaterError,
+};
+
+pub const CONFIG_FILE_NAME: &str = "collapse_updater.toml";
+
+const DEFAULT_API_URL: &str = "https://api.github.com";
+const DEFAULT_REPO: &str = "dest4590/CollapseLoader";
+const DEFAULT_UPDATER_REPO: &str = "CollapseLoader/CollapseUpdater";
+const DEFAULT_KEEP: usize = 1;
+const DEFAULT_RETRY_ATTEMPTS: u32 = 4;
+const DEFAULT_RETRY_MAX_DELAY: u64 = 30;
+const DEFAULT_CONNECT_TIMEOUT: u64 = 15;
+const DEFAULT_READ_TIMEOUT: u64 = 30;
+
+/// Settings as they appear in `collapse_updater.toml`, every field optional.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    repo: Option<String>,
+    updater_repo: Option<String>,
+    self_update: Option<bool>,
+    delta: Option<bool>,
+    api_url: Option<String>,
+    download_url: Option<String>,
+    mirrors: Option<Vec<String>>,
+    asset: Option<String>,
+    entry: Option<String>,
+    prerelease: Option<bool>,
+    version: Option<String>,
+    keep: Option<usize>,
+    token: Option<String>,
+    check_interval: Option<u64>,
+    retry_attempts: Option<u32>,
+    retry_max_delay: Option<u64>,
+    proxy: Option<String>,
+    ca_certs: Option<Vec<PathBuf>>,
+    connect_timeout: Option<u64>,
+    read_timeout: Option<u64>,
+    launch_mode: Option<LaunchMode>,
+    install_dir: Option<PathBuf>,
+}
+
+/// Resolved updater settings. Command line flags win over environment
+/// variables (both handled by [`Options`]), which win over the config file
+/// next to the executable.
+pub struct Config {
+    pub owner: String,
+    pub repo: String,
+    /// `owner/name` of the repository this updater itself is released from.
+    pub updater_repo: String,
+    /// Replace this executable with a newer updater release before running.
+    pub self_update: bool,
+    /// Update from a patch against the installed build when the release
+    /// has one.
+    pub delta: bool,
+    pub api_url: String,
+    pub download_url: Option<Url>,
+    /// Base URLs of mirrors serving a `releases.json` manifest, in the
+    /// order they are tried.
+    pub mirrors: Vec<Url>,
+    /// Glob matched against asset names, e.g. `CollapseLoader*.exe`.
+    pub asset_pattern: Option<Pattern>,
+    /// Glob for the executable to start in archived builds, overriding
+    /// their manifest.
+    pub entry_pattern: Option<String>,
+    pub pre_release: bool,
+    /// API token, sent only to `api_url`.
+    pub token: Option<String>,
+    /// Seconds to reuse cached release info before asking the API again.
+    pub check_interval: u64,
+    pub retry: RetryPolicy,
+    /// Explicit proxy URL, credentials included.
+    pub proxy: Option<String>,
+    /// PEM files with extra root certificates, e.g. for TLS inspection.
+    pub ca_certs: Vec<PathBuf>,
+    pub connect_timeout: Duration,
+    /// Longest silence on an open connection before it counts as hung.
+    pub read_timeout: Duration,
+    /// Where builds, the state file and the release cache are kept.
+    pub install_dir: PathBuf,
+    /// Release tag to install instead of the latest one.
+    pub version: Option<String>,
+    /// Relaunch the previously installed build instead of updating.
+    pub rollback: bool,
+    /// Launch the newest local build without touching the network.
+    pub offline: bool,
+    pub launch_mode: LaunchMode,
+    /// Launch builds without a valid signature.
+    pub allow_unsigned: bool,
+    /// How many previous builds to keep around for rollbacks.
+    pub keep: usize,
+    /// Arguments that are not meant for the updater, passed on to the loader.
+    pub loader_args: Vec<String>,
+}
+
+impl Config {
+    pub fn load(options: &Options, loader_args: Vec<String>) -> Result<Self, UpdaterError> {
+        let file = load_file()?;
+
+        let full_repo = options
+            .repo
+            .clone()
+            .or(file.repo)
+            .unwrap_or_else(|| DEFAULT_REPO.to_string());
+        let (owner, repo) = split_repo(&full_repo)?;
+
+        let updater_repo = options
+            .updater_repo
+            .clone()
+            .or(file.updater_repo)
+            .unwrap_or_else(|| DEFAULT_UPDATER_REPO.to_string());
+        split_repo(&updater_repo)?;
+
+        let api_url = options
+            .api_url
+            .clone()
+            .or(file.api_url)
+            .unwrap_or_else(|| DEFAULT_API_URL.to_string())
+            .trim_end_matches('/')
+            .to_string();
+
+        let download_url = options
+            .download_url
+            .clone()
+            .or(file.download_url)
+            .map(|url| parse_url(&url))
+            .transpose()?;
+
+        let asset_pattern = options
+            .asset
+            .clone()
+            .or(file.asset)
+            .map(|pattern| parse_glob(&pattern))
+            .transpose()?;
+
+        let mirrors = if options.mirrors.is_empty() {
+            file.mirrors.unwrap_or_default()
+        } else {
+            options.mirrors.clone()
+        };
+        let mirrors = mirrors
+            .iter()
+            .map(|mirror| parse_url(&format!("{}/", mirror.trim_end_matches('/'))))
+            .collect::<Result<_, _>>()?;
+
+        let install_dir = match (&options.install_dir, file.install_dir) {
+            (Some(dir), _) => absolute(dir)?,
+            (None, Some(dir)) => resolve_path(&dir),
+            (None, None) => exe_dir()?,
+        };
+
+        Ok(Config {
+            owner,
+            repo,
+            updater_repo,
+            self_update: !options.no_self_update && file.self_update.unwrap_or(true),
+            delta: !options.no_delta && file.delta.unwrap_or(true),
+            api_url,
+            download_url,
+            mirrors,
+            asset_pattern,
+            entry_pattern: options.entry.clone().or(file.entry),
+            pre_release: options.prerelease || file.prerelease.unwrap_or(false),
+            token: env_var("GITHUB_TOKEN").or(file.token),
+            check_interval: options.check_interval.or(file.check_interval).unwrap_or(0),
+            retry: RetryPolicy {
+                attempts: options
+                    .retry_attempts
+                    .or(file.retry_attempts)
+                    .unwrap_or(DEFAULT_RETRY_ATTEMPTS)
+                    .max(1),
+                max_delay: Duration::from_secs(
+                    options
+                        .retry_max_delay
+                        .or(file.retry_max_delay)
+                        .unwrap_or(DEFAULT_RETRY_MAX_DELAY),
+                ),
+            },
+            proxy: options.proxy.clone().or(file.proxy),
+            ca_certs: file
+                .ca_certs
+                .unwrap_or_default()
+                .into_iter()
+                .map(|path| resolve_path(&path))
+                .chain(options.ca_cert.iter().cloned())
+                .collect(),
+            connect_timeout: Duration::from_secs(
+                options
+                    .connect_timeout
+                    .or(file.connect_timeout)
+                    .unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+            ),
+            read_timeout: Duration::from_secs(
+                options
+                    .read_timeout
+                    .or(file.read_timeout)
+                    .unwrap_or(DEFAULT_READ_TIMEOUT),
+            ),
+            install_dir,
+            version: options.version.clone().or(file.version),
+            rollback: options.rollback,
+            offline: options.offline,
+            launch_mode: options.launch_mode.or(file.launch_mode).unwrap_or_default(),
+            allow_unsigned: options.allow_unsigned,
+            keep: options.keep.or(file.keep).unwrap_or(DEFAULT_KEEP),
+            loader_args,
+        })
+    }
+
+    pub fn releases_url(&self) -> String {
+        format!(
+            "{}/repos/{}/{}/releases",
+            self.api_url, self.owner, self.repo
+        )
+    }
+
+    pub fn updater_releases_url(&self) -> String {
+        format!("{}/repos/{}/releases", self.api_url, self.updater_repo)
+    }
+
+    /// Directory a build of release `tag` is installed into.
+    pub fn version_dir(&self, tag: &str) -> PathBuf {
+        self.install_dir
+            .join(VERSIONS_DIR)
+            .join(safe_file_name(tag))
+    }
+
+    /// Points an asset URL returned by the API at the configured download
+    /// host, keeping its path.
+    pub fn asset_url(&self, browser_download_url: &str) -> Result<String, UpdaterError> {
+        let Some(base) = &self.download_url else {
+            return Ok(browser_download_url.to_string());
+        };
+
+        let original = parse_url(browser_download_url)?;
+        let mut url = base.clone();
+        url.set_path(&format!(
+            "{}/{}",
+            base.path().trim_end_matches('/'),
+            original.path().trim_start_matches('/')
+        ));
+        url.set_query(original.query());
+
+        Ok(url.to_string())
+    }
+}
+
+fn split_repo(full_repo: &str) -> Result<(String, String), UpdaterError> {
+    match full_repo.split_once('/') {
+        Some((owner, repo)) if !owner.is_empty() && !repo.is_empty() => {
+            Ok((owner.to_string(), repo.to_string()))
+        }
+        _ => Err(UpdaterError::Config(format!(
+            "Repository must be in the form owner/name, got: {}",
+            full_repo
+        ))),
+    }
+}
+
+fn config_path() -> Option<PathBuf> {
+    exe_dir().ok().map(|dir| dir.join(CONFIG_FILE_NAME))
+}
+
+/// The directory of the updater executable, the default install root.
+fn exe_dir() -> Result<PathBuf, UpdaterError> {
+    env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(Path::to_path_buf))
+        .ok_or_else(|| UpdaterError::Config("Failed to locate the updater executable".to_string()))
+}
+
+/// Makes a path from the command line or environment independent of the
+/// working directory.
+fn absolute(path: &Path) -> Result<PathBuf, UpdaterError> {
+    std::path::absolute(path)
+        .map_err(|err| UpdaterError::Config(format!("Invalid path {}: {}", path.display(), err)))
+}
+
+/// Relative paths in the config file are relative to the file itself.
+fn resolve_path(path: &Path) -> PathBuf {
+    match config_path().as_deref().and_then(Path::parent) {
+        Some(dir) if path.is_relative() => dir.join(path),
+        _ => path.to_path_buf(),
+    }
+}
+
+fn load_file() -> Result<FileConfig, UpdaterError> {
+    let Some(path) = config_path().filter(|path| path.exists()) else {
+        return Ok(FileConfig::default());
+    };
+
+    let contents = fs::read_to_string(&path).map_err(|err| {
+        UpdaterError::Config(format!("Failed to read {}: {}", path.display(), err))
+    })?;
+
+    toml::from_str(&contents)
+        .map_err(|err| UpdaterError::Config(format!("{}: {}", path.display(), err)))
+}
+
+fn env_var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+fn parse_url(url: &str) -> Result<Url, UpdaterError> {
+    Url::parse(url).map_err(|err| UpdaterError::Config(format!("Invalid URL {}: {}", url, err)))
+}
+
+fn parse_glob(pattern: &str) -> Result<Pattern, UpdaterError> {
+    Pattern::new(pattern)
+        .map_err(|err| UpdaterError::Config(format!("Invalid asset pattern {}: {}", pattern, err)))
+}
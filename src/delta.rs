@@ -0,0 +1,183 @@
+//! Updating from a binary patch against the installed build instead of
+//! downloading the whole new one.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufReader},
+    path::Path,
+};
+
+use console::style;
+use reqwest::Client;
+
+use crate::{
+    config::Config,
+    download::{self, PART_SUFFIX},
+    install::{file_sha256, safe_file_name, with_suffix},
+    output::{self, say, Event},
+    release::{Asset, Release},
+    report,
+    state::State,
+    UpdaterError,
+};
+
+/// Patches are published as `<asset><PATCH_INFIX><base tag><PATCH_SUFFIX>`,
+/// e.g. `CollapseLoader-1.1.exe.from-v1.0.zst`, made with
+/// `zstd --patch-from=<base build> <new build>`.
+const PATCH_INFIX: &str = ".from-";
+const PATCH_SUFFIX: &str = ".zst";
+
+/// Added to the build while the patched result is being checked.
+pub const PATCHED_SUFFIX: &str = ".patched.part";
+
+/// Largest zstd window accepted, 1 GiB. `--patch-from` sizes the window to
+/// cover the base build, which loader builds stay well below.
+const WINDOW_LOG_MAX: u32 = 30;
+
+/// Whether a release asset is a patch rather than a build.
+pub fn is_patch(name: &str) -> bool {
+    name.ends_with(PATCH_SUFFIX) && name.contains(PATCH_INFIX)
+}
+
+/// Whether `part` is the partial download of a patch for `build`, and so
+/// worth keeping to resume.
+pub fn is_patch_part(part: &Path, build: &Path) -> bool {
+    let name = |path: &Path| {
+        path.file_name()
+            .and_then(|name| name.to_str())
+            .map(String::from)
+    };
+    let (Some(part_name), Some(build_name)) = (name(part), name(build)) else {
+        return false;
+    };
+
+    part.parent() == build.parent()
+        && part_name
+            .strip_prefix(&build_name)
+            .and_then(|rest| rest.strip_suffix(PART_SUFFIX))
+            .is_some_and(is_patch)
+}
+
+/// Tries to produce `build`, the download of `asset`, by patching the
+/// installed build. Only done when the release has a patch from the
+/// installed version and a checksum to check the result against. Returns
+/// whether it worked; on any failure the reason is printed and the caller
+/// downloads the full build instead.
+pub async fn update_from_patch(
+    client: &Client,
+    config: &Config,
+    state: &State,
+    release: &Release,
+    asset: &Asset,
+    build: &Path,
+    expected_sha256: Option<&str>,
+) -> bool {
+    if !config.delta {
+        return false;
+    }
+    let Some(expected_sha256) = expected_sha256 else {
+        return false;
+    };
+    let (Some(base_tag), Some(base)) = (&state.installed_version, state.current_install()) else {
+        return false;
+    };
+    let patch_name = format!("{}{}{}{}", asset.name, PATCH_INFIX, base_tag, PATCH_SUFFIX);
+    let Some(patch) = release.assets.iter().find(|a| a.name == patch_name) else {
+        return false;
+    };
+
+    say!(
+        "{} {} ({} instead of {} bytes)",
+        style(format!("\nPatching {} to {}:", base_tag, release.tag_name)).blue(),
+        build.display(),
+        patch.size,
+        asset.size
+    );
+
+    match apply(client, config, patch, &base, build, expected_sha256).await {
+        Ok(()) => {
+            output::emit(Event::Downloaded {
+                file: build,
+                source: &patch.browser_download_url,
+            });
+            true
+        }
+        Err(err) => {
+            eprintln!(
+                "{} {}",
+                style("Patch failed, downloading the full build:").yellow(),
+                report(&err)
+            );
+            false
+        }
+    }
+}
+
+/// Downloads `patch`, applies it to `base` and moves the result to `build`
+/// once its checksum matches.
+async fn apply(
+    client: &Client,
+    config: &Config,
+    patch: &Asset,
+    base: &Path,
+    build: &Path,
+    expected_sha256: &str,
+) -> Result<(), UpdaterError> {
+    let patch_file = build.with_file_name(safe_file_name(&patch.name));
+    download::download(
+        client,
+        &config.asset_url(&patch.browser_download_url)?,
+        &patch_file,
+        patch.size,
+        None,
+        &config.retry,
+    )
+    .await?;
+
+    let patched = with_suffix(build, PATCHED_SUFFIX);
+    let applied = patch_into(base, &patch_file, &patched);
+    let _ = fs::remove_file(&patch_file);
+    if let Err(err) = applied {
+        let _ = fs::remove_file(&patched);
+        return Err(UpdaterError::io(format!(
+            "Failed to apply {} to {}",
+            patch.name,
+            base.display()
+        ))(err));
+    }
+
+    let actual = file_sha256(&patched).map_err(UpdaterError::io(format!(
+        "Failed to read {}",
+        patched.display()
+    )))?;
+    let ok = actual == expected_sha256;
+    output::emit(Event::Verification {
+        file: build,
+        expected: Some(expected_sha256),
+        actual: &actual,
+        ok,
+    });
+    if !ok {
+        let _ = fs::remove_file(&patched);
+        return Err(UpdaterError::ChecksumMismatch {
+            expected: expected_sha256.to_string(),
+            actual,
+        });
+    }
+
+    fs::rename(&patched, build).map_err(UpdaterError::io(format!(
+        "Failed to move {} into place",
+        build.display()
+    )))
+}
+
+/// Decompresses `patch` with `base` as the reference it was made against.
+fn patch_into(base: &Path, patch: &Path, out: &Path) -> io::Result<()> {
+    let base = fs::read(base)?;
+    let mut decoder =
+        zstd::stream::read::Decoder::with_ref_prefix(BufReader::new(File::open(patch)?), &base)?;
+    decoder.window_log_max(WINDOW_LOG_MAX)?;
+
+    io::copy(&mut decoder, &mut File::create(out)?)?;
+    Ok(())
+}
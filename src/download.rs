@@ -0,0 +1,481 @@
+use std::{
+    cmp::min,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use console::style;
+use futures::stream::StreamExt;
+use indicatif::ProgressBar;
+use reqwest::{
+    header::{self, HeaderMap},
+    Client, StatusCode,
+};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    archive, delta,
+    install::{version_in_name, with_suffix, VERSIONS_DIR},
+    output::{self, say, Event},
+    retry::{self, RetryPolicy},
+    UpdaterError,
+};
+
+pub const PART_SUFFIX: &str = ".part";
+
+pub fn part_path(file: &Path) -> PathBuf {
+    with_suffix(file, PART_SUFFIX)
+}
+
+/// Downloads `url` into `<file>.part`, resuming from whatever is already
+/// on disk when the server honours range requests, and renames it to
+/// `file` once the checksum (if any) matches. Interrupted attempts are
+/// retried according to `retry`, picking up where they stopped.
+pub async fn download(
+    client: &Client,
+    url: &str,
+    file: &Path,
+    total_size: u64,
+    expected_sha256: Option<&str>,
+    retry: &RetryPolicy,
+) -> Result<(), UpdaterError> {
+    if let Some(dir) = file.parent() {
+        fs::create_dir_all(dir).map_err(UpdaterError::io(format!(
+            "Failed to create {}",
+            dir.display()
+        )))?;
+    }
+
+    let part = part_path(file);
+    let pb = output::progress_bar(total_size);
+
+    let mut attempt = 0;
+    let hasher = loop {
+        match download_part(client, url, file, &part, total_size, &pb).await {
+            Ok(hasher) => break hasher,
+            Err(AttemptError::Transient(err)) if retry.should_retry(attempt) => {
+                retry.wait(attempt, &err).await;
+                attempt += 1;
+            }
+            Err(AttemptError::Transient(err) | AttemptError::Fatal(err)) => {
+                pb.abandon();
+                return Err(err);
+            }
+        }
+    };
+
+    let actual_sha256 = format!("{:x}", hasher.finalize());
+    output::emit(Event::Verification {
+        file,
+        expected: expected_sha256,
+        actual: &actual_sha256,
+        ok: expected_sha256.is_none_or(|expected| expected == actual_sha256),
+    });
+    if let Some(expected) = expected_sha256 {
+        if actual_sha256 != expected {
+            pb.abandon_with_message(format!(
+                "{} {}",
+                style("Checksum verification failed:").red().bold(),
+                file.display()
+            ));
+            let _ = fs::remove_file(&part);
+            return Err(UpdaterError::ChecksumMismatch {
+                expected: expected.to_string(),
+                actual: actual_sha256,
+            });
+        }
+    }
+
+    // Same directory, so the rename is atomic: `file` is either the old
+    // file or the complete, verified new one, never a truncated download.
+    fs::rename(&part, file).map_err(UpdaterError::io("Failed to rename downloaded file"))?;
+
+    pb.finish_with_message(format!(
+        "{} {}",
+        style("Downloaded successfully:").green().bold(),
+        file.display()
+    ));
+
+    Ok(())
+}
+
+/// Why a download attempt stopped before the whole file was on disk.
+enum AttemptError {
+    /// Worth another attempt: the connection dropped or the server hiccuped.
+    Transient(UpdaterError),
+    Fatal(UpdaterError),
+}
+
+fn fatal_io(context: &str) -> impl FnOnce(io::Error) -> AttemptError + '_ {
+    move |err| AttemptError::Fatal(UpdaterError::io(context)(err))
+}
+
+fn request_error(err: reqwest::Error) -> AttemptError {
+    let transient = retry::is_transient_error(&err);
+    let err = UpdaterError::network("Error downloading file")(err);
+    if transient {
+        AttemptError::Transient(err)
+    } else {
+        AttemptError::Fatal(err)
+    }
+}
+
+/// Brings `part` up to `total_size` bytes and returns the hash of its
+/// contents.
+async fn download_part(
+    client: &Client,
+    url: &str,
+    file: &Path,
+    part: &Path,
+    total_size: u64,
+    pb: &ProgressBar,
+) -> Result<Sha256, AttemptError> {
+    let mut offset = fs::metadata(part).map(|m| m.len()).unwrap_or(0);
+    if offset > total_size {
+        offset = 0;
+    }
+
+    let mut hasher = Sha256::new();
+    if offset > 0 {
+        io::copy(
+            &mut File::open(part).map_err(fatal_io("Failed to open partial file"))?,
+            &mut hasher,
+        )
+        .map_err(fatal_io("Failed to read partial file"))?;
+    }
+
+    pb.set_position(offset);
+    if offset >= total_size {
+        return Ok(hasher);
+    }
+
+    let mut request = client.get(url);
+    if offset > 0 {
+        request = request.header(header::RANGE, format!("bytes={}-", offset));
+    }
+
+    let mut res = request.send().await.map_err(request_error)?;
+
+    let resumed = offset > 0 && is_resumed_response(res.status(), res.headers(), offset);
+    if offset > 0 && !resumed {
+        say!(
+            "{}",
+            style("Server does not support resuming, restarting download").yellow()
+        );
+        offset = 0;
+        hasher = Sha256::new();
+
+        // e.g. 416 Range Not Satisfiable: ask again for the whole file
+        if !res.status().is_success() {
+            res = client.get(url).send().await.map_err(request_error)?;
+        }
+    } else if resumed {
+        say!("{} {}", style("Resuming download at byte").blue(), offset);
+    }
+
+    if !res.status().is_success() {
+        let err = UpdaterError::HttpStatus {
+            code: res.status().as_u16(),
+            body: String::new(),
+        };
+        return Err(if retry::is_transient_status(res.status()) {
+            AttemptError::Transient(err)
+        } else {
+            AttemptError::Fatal(err)
+        });
+    }
+
+    let mut out = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(resumed)
+        .truncate(!resumed)
+        .open(part)
+        .map_err(fatal_io("Failed to create file"))?;
+
+    pb.set_message("Downloading...");
+    pb.set_position(offset);
+    output::emit(Event::DownloadStarted {
+        file,
+        size: total_size,
+        offset,
+    });
+
+    let mut downloaded = offset;
+    let mut reported_percent = 0;
+    let mut stream = res.bytes_stream();
+    while let Some(item) = stream.next().await {
+        let chunk = item.map_err(request_error)?;
+        out.write_all(&chunk)
+            .map_err(fatal_io("Error writing to file"))?;
+        hasher.update(&chunk);
+
+        downloaded = min(downloaded + (chunk.len() as u64), total_size);
+        pb.set_position(downloaded);
+
+        let percent = downloaded * 100 / total_size.max(1);
+        if percent > reported_percent {
+            reported_percent = percent;
+            output::emit(Event::DownloadProgress {
+                file,
+                downloaded,
+                size: total_size,
+            });
+        }
+    }
+
+    // Make sure the data is on disk before the rename makes it visible
+    out.sync_all().map_err(fatal_io("Failed to flush file"))?;
+
+    if downloaded < total_size {
+        return Err(AttemptError::Transient(UpdaterError::IncompleteDownload {
+            received: downloaded,
+            expected: total_size,
+        }));
+    }
+
+    Ok(hasher)
+}
+
+/// Removes leftover `.part` files of earlier interrupted downloads and
+/// half-unpacked bundles from the version directories under `root`. The one for `resume` is kept so it
+/// can still be resumed; before it is known which build is wanted, the
+/// ones in the newest version directory are kept, since that is where an
+/// interrupted update was headed.
+pub fn delete_stale_parts(root: &Path, resume: Option<&Path>) -> Result<(), io::Error> {
+    let versions = root.join(VERSIONS_DIR);
+    if !versions.is_dir() {
+        return Ok(());
+    }
+
+    let dirs: Vec<PathBuf> = fs::read_dir(&versions)?
+        .filter_map(|res| res.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .collect();
+    let newest = dirs.iter().max_by_key(|dir| {
+        dir.file_name()
+            .and_then(|name| name.to_str())
+            .map(version_in_name)
+            .unwrap_or_default()
+    });
+
+    for dir in &dirs {
+        let Ok(entries) = fs::read_dir(dir) else {
+            continue;
+        };
+
+        for entry in entries.filter_map(|res| res.ok()) {
+            let path = entry.path();
+            let name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or_default();
+            if name.ends_with(archive::STAGING_SUFFIX) && path.is_dir() {
+                // Bundles are unpacked in one go, so this one never finished
+                if let Err(e) = fs::remove_dir_all(&path) {
+                    eprintln!(
+                        "{} {}: {}",
+                        style("Failed to delete").red(),
+                        path.display(),
+                        e
+                    );
+                }
+                continue;
+            }
+
+            let keep = match resume {
+                Some(file) => path == part_path(file) || delta::is_patch_part(&path, file),
+                None => Some(dir) == newest && !name.ends_with(delta::PATCHED_SUFFIX),
+            };
+            if !name.ends_with(PART_SUFFIX) || keep {
+                continue;
+            }
+
+            match fs::remove_file(&path) {
+                Ok(_) => {
+                    say!(
+                        "{} {}",
+                        style("Deleted stale download:").red(),
+                        path.display()
+                    );
+                    output::emit(Event::Deleted { file: &path });
+                }
+                Err(e) => eprintln!(
+                    "{} {}: {}",
+                    style("Failed to delete").red(),
+                    path.display(),
+                    e
+                ),
+            }
+        }
+
+        // Only succeeds if nothing but the partial download was in there
+        let _ = fs::remove_dir(dir);
+    }
+
+    Ok(())
+}
+
+/// A resumed response must be `206 Partial Content` starting exactly at
+/// `offset`; anything else means the server sent the whole file again.
+fn is_resumed_response(status: StatusCode, headers: &HeaderMap, offset: u64) -> bool {
+    if status != StatusCode::PARTIAL_CONTENT {
+        return false;
+    }
+
+    headers
+        .get(header::CONTENT_RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("bytes "))
+        .and_then(|range| range.split('-').next())
+        .and_then(|start| start.parse::<u64>().ok())
+        == Some(offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader},
+        net::TcpListener,
+        thread,
+        time::Duration,
+    };
+
+    use super::*;
+
+    const BODY: &[u8] = b"0123456789abcdefghij";
+
+    fn content_range(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_RANGE, value.parse().unwrap());
+        headers
+    }
+
+    /// Serves [`BODY`] on a local port, answering range requests with
+    /// `range` and everything else with the whole file.
+    fn serve(range: fn(u64) -> Vec<u8>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/build", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut offset = None;
+                for line in BufReader::new(&stream).lines() {
+                    let line = line.unwrap().to_ascii_lowercase();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(start) = line.strip_prefix("range: bytes=") {
+                        offset = start.trim_end_matches('-').parse().ok();
+                    }
+                }
+
+                let response = match offset {
+                    Some(offset) => range(offset),
+                    None => [
+                        format!(
+                            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                            BODY.len()
+                        )
+                        .as_bytes(),
+                        BODY,
+                    ]
+                    .concat(),
+                };
+                let _ = stream.write_all(&response);
+            }
+        });
+        url
+    }
+
+    fn partial_content(offset: u64) -> Vec<u8> {
+        let rest = &BODY[offset as usize..];
+        [
+            format!(
+                "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                offset,
+                BODY.len() - 1,
+                BODY.len(),
+                rest.len()
+            )
+            .as_bytes(),
+            rest,
+        ]
+        .concat()
+    }
+
+    fn range_not_satisfiable(_: u64) -> Vec<u8> {
+        b"HTTP/1.1 416 Range Not Satisfiable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            .to_vec()
+    }
+
+    /// Downloads from `url` over a `.part` file holding `part`.
+    async fn download_over(url: &str, part: &[u8]) -> Vec<u8> {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("build");
+        fs::write(part_path(&file), part).unwrap();
+        let retry = RetryPolicy {
+            attempts: 1,
+            max_delay: Duration::ZERO,
+        };
+        let expected = format!("{:x}", Sha256::digest(BODY));
+
+        download(
+            &Client::builder().no_proxy().build().unwrap(),
+            url,
+            &file,
+            BODY.len() as u64,
+            Some(&expected),
+            &retry,
+        )
+        .await
+        .unwrap();
+        assert!(!part_path(&file).exists());
+        fs::read(&file).unwrap()
+    }
+
+    #[test]
+    fn resumed_responses_start_at_the_offset() {
+        let headers = content_range("bytes 100-199/200");
+        assert!(is_resumed_response(
+            StatusCode::PARTIAL_CONTENT,
+            &headers,
+            100
+        ));
+        assert!(!is_resumed_response(
+            StatusCode::PARTIAL_CONTENT,
+            &headers,
+            50
+        ));
+    }
+
+    #[test]
+    fn full_responses_are_not_resumed() {
+        let headers = content_range("bytes 100-199/200");
+        assert!(!is_resumed_response(StatusCode::OK, &headers, 100));
+        assert!(!is_resumed_response(
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            &content_range("bytes */200"),
+            100
+        ));
+        assert!(!is_resumed_response(
+            StatusCode::PARTIAL_CONTENT,
+            &HeaderMap::new(),
+            100
+        ));
+    }
+
+    #[tokio::test]
+    async fn resumes_where_the_part_file_stops() {
+        let url = serve(partial_content);
+        assert_eq!(download_over(&url, &BODY[..8]).await, BODY);
+    }
+
+    #[tokio::test]
+    async fn restarts_when_the_range_is_refused() {
+        let url = serve(range_not_satisfiable);
+        assert_eq!(download_over(&url, b"garbage").await, BODY);
+    }
+}
@@ -0,0 +1,425 @@
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use console::style;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    archive,
+    download::PART_SUFFIX,
+    output::{self, say, Event},
+    platform,
+    signature::signature_path,
+    state::State,
+    UpdaterError,
+};
+
+/// Directory under the install root holding one `<tag>/` directory per
+/// installed release.
+pub const VERSIONS_DIR: &str = "versions";
+
+pub fn file_sha256(file_path: &Path) -> Result<String, io::Error> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(file_path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+pub fn is_file_already_downloaded(
+    file_path: &Path,
+    expected_size: u64,
+    expected_sha256: Option<&str>,
+) -> bool {
+    if file_path.exists() {
+        if let Ok(metadata) = std::fs::metadata(file_path) {
+            if metadata.len() == expected_size {
+                if let Some(expected) = expected_sha256 {
+                    if file_sha256(file_path).ok().as_deref() != Some(expected) {
+                        say!(
+                            "{} {}",
+                            style("Checksum mismatch, downloading again:").yellow(),
+                            file_path.display()
+                        );
+                        return false;
+                    }
+                }
+
+                say!(
+                    "{} {}",
+                    style("Already downloaded:").yellow(),
+                    file_path.display()
+                );
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// File or directory name for a release tag or asset name, with anything
+/// that isn't safe in a file name replaced.
+pub fn safe_file_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | '+') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    // "", "." and ".." would point somewhere else entirely
+    if name.chars().all(|c| c == '.') {
+        name.replace('.', "_") + "_"
+    } else {
+        name
+    }
+}
+
+/// Appends `suffix` to the file name of `path`, e.g. `.part`.
+pub fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Whether a file in a version directory is a build: something that can be
+/// started or unpacked, rather than a partial download, signature or other
+/// leftover.
+fn is_build(path: &Path) -> bool {
+    let is_part = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.ends_with(PART_SUFFIX));
+    !is_part && (platform::is_runnable(path) || archive::is_archive(path))
+}
+
+/// Every build in the version directories under `root`.
+pub fn local_builds(root: &Path) -> Result<Vec<PathBuf>, io::Error> {
+    let versions = root.join(VERSIONS_DIR);
+    if !versions.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut builds = Vec::new();
+    for version in fs::read_dir(&versions)?.filter_map(|res| res.ok()) {
+        if !version.file_type().map(|ft| ft.is_dir()).unwrap_or(false) {
+            continue;
+        }
+
+        let Ok(entries) = fs::read_dir(version.path()) else {
+            continue;
+        };
+        builds.extend(
+            entries
+                .filter_map(|res| res.ok())
+                .filter(|entry| entry.file_type().map(|ft| ft.is_file()).unwrap_or(false))
+                .map(|entry| entry.path())
+                .filter(|path| is_build(path)),
+        );
+    }
+
+    Ok(builds)
+}
+
+/// Moves the builds that updaters from before the versions layout left
+/// next to themselves, `CollapseLoader*.exe` directly in `root`, into
+/// version directories of their own, so they are launched, rolled back to
+/// and cleaned up like any other build. The installed one goes into the
+/// directory of the installed tag, the others into one named after them.
+pub fn migrate_flat_builds(root: &Path, state: &mut State) -> Result<(), io::Error> {
+    let current = state.current_install();
+    let mut moved = false;
+
+    for entry in fs::read_dir(root)?.filter_map(|res| res.ok()) {
+        let path = entry.path();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        if !name.starts_with("CollapseLoader") || !name.ends_with(".exe") || !path.is_file() {
+            continue;
+        }
+
+        let dir_name = match &state.installed_version {
+            Some(tag) if current.as_deref() == Some(path.as_path()) => safe_file_name(tag),
+            _ => safe_file_name(name.trim_end_matches(".exe")),
+        };
+        let build = root.join(VERSIONS_DIR).join(dir_name).join(name);
+        if build.exists() {
+            continue;
+        }
+
+        let result = build
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::rename(&path, &build));
+        if let Err(err) = result {
+            eprintln!(
+                "{} {}: {}",
+                style("Failed to move").red(),
+                path.display(),
+                err
+            );
+            continue;
+        }
+        let _ = fs::rename(signature_path(&path), signature_path(&build));
+
+        say!("{} {}", style("Moved build to:").blue(), build.display());
+        state.record_move(&path, &build);
+        moved = true;
+    }
+
+    if moved {
+        state.save()?;
+    }
+    Ok(())
+}
+
+/// Deletes the version directories of every build except the ones listed
+/// in `keep` and any that are still running. With `dry_run` it only lists
+/// them.
+pub fn delete_old(root: &Path, keep: &[PathBuf], dry_run: bool) -> Result<(), io::Error> {
+    let mut dirs: Vec<PathBuf> = local_builds(root)?
+        .iter()
+        .filter(|build| !keep.contains(build))
+        .filter_map(|build| build.parent())
+        // Another format of a kept release may share its directory
+        .filter(|dir| !keep.iter().any(|kept| kept.parent() == Some(*dir)))
+        .map(Path::to_path_buf)
+        .collect();
+    dirs.sort();
+    dirs.dedup();
+
+    for dir in &dirs {
+        let dir = dir.as_path();
+        if is_in_use(dir) {
+            say!(
+                "{} {}",
+                style("Still running, not deleting:").yellow(),
+                dir.display()
+            );
+            continue;
+        }
+
+        if dry_run {
+            say!("{} {}", style("Would delete:").yellow(), dir.display());
+            output::emit(Event::WouldDelete { file: dir });
+            continue;
+        }
+
+        match fs::remove_dir_all(dir) {
+            Ok(_) => {
+                say!("{} {}", style("Deleted:").red(), dir.display());
+                output::emit(Event::Deleted { file: dir });
+            }
+            Err(e) => eprintln!(
+                "{} {}: {}",
+                style("Failed to delete").red(),
+                dir.display(),
+                e
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether some process is running an executable from `dir`, going by the
+/// executables linked from `/proc`.
+#[cfg(target_os = "linux")]
+fn is_in_use(dir: &Path) -> bool {
+    let Ok(dir) = dir.canonicalize() else {
+        return false;
+    };
+    let Ok(processes) = fs::read_dir("/proc") else {
+        return false;
+    };
+
+    processes
+        .filter_map(|res| res.ok())
+        .filter_map(|process| fs::read_link(process.path().join("exe")).ok())
+        .any(|exe| exe.starts_with(&dir))
+}
+
+/// Whether some process is running an executable from `dir`. Windows
+/// refuses to open a running executable for exclusive writing.
+#[cfg(windows)]
+fn is_in_use(dir: &Path) -> bool {
+    use std::os::windows::fs::OpenOptionsExt;
+
+    archive::files_in(dir)
+        .iter()
+        .filter(|file| platform::is_runnable(file))
+        .any(|file| {
+            fs::OpenOptions::new()
+                .write(true)
+                .share_mode(0)
+                .open(file)
+                .is_err()
+        })
+}
+
+/// No cheap way to tell elsewhere; deleting a running build is harmless
+/// there anyway, since the process keeps its open files.
+#[cfg(not(any(target_os = "linux", windows)))]
+fn is_in_use(_dir: &Path) -> bool {
+    false
+}
+
+/// What to start for an installed build, and where.
+pub struct EntryPoint {
+    pub executable: PathBuf,
+    /// The bundle an archived build was unpacked into, or the version
+    /// directory of a single-file build.
+    pub working_dir: PathBuf,
+}
+
+/// What to start for an installed `build`: the build itself, or for an
+/// archive the entry point inside its unpacked copy (see
+/// [`archive::find_entry`]). Archives are unpacked if they haven't been
+/// yet, or again if `fresh` (just downloaded).
+pub fn entry_point(
+    build: &Path,
+    fresh: bool,
+    pattern: Option<&str>,
+) -> Result<EntryPoint, UpdaterError> {
+    let entry = if archive::is_archive(build) {
+        let dir = archive::extract_dir(build);
+        let dir = if fresh || !dir.is_dir() {
+            archive::extract(build)?
+        } else {
+            dir
+        };
+        let root = archive::bundle_root(&dir);
+        EntryPoint {
+            executable: archive::find_entry(&root, build, pattern)?,
+            working_dir: root,
+        }
+    } else {
+        EntryPoint {
+            executable: build.to_path_buf(),
+            working_dir: build.parent().unwrap_or(build).to_path_buf(),
+        }
+    };
+
+    platform::make_executable(&entry.executable).map_err(UpdaterError::io(format!(
+        "Failed to make {} executable",
+        entry.executable.display()
+    )))?;
+    Ok(entry)
+}
+
+/// The newest local build, judged by the version of its directory and then
+/// by modification time.
+pub fn newest_local_build(root: &Path) -> Result<Option<PathBuf>, io::Error> {
+    Ok(local_builds(root)?.into_iter().max_by_key(|build| {
+        let modified = fs::metadata(build)
+            .and_then(|metadata| metadata.modified())
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        let version = build
+            .parent()
+            .and_then(Path::file_name)
+            .and_then(|name| name.to_str())
+            .map(version_in_name)
+            .unwrap_or_default();
+        (version, modified)
+    }))
+}
+
+/// Extracts the first dotted number sequence, e.g. `[1, 4, 2]` from
+/// `v1.4.2`.
+pub fn version_in_name(filename: &str) -> Vec<u64> {
+    let start = match filename.find(|c: char| c.is_ascii_digit()) {
+        Some(start) => start,
+        None => return Vec::new(),
+    };
+
+    filename[start..]
+        .split(|c: char| !c.is_ascii_digit() && c != '.')
+        .next()
+        .unwrap_or_default()
+        .split('.')
+        .map_while(|part| part.parse().ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn safe_file_name_keeps_safe_tags() {
+        assert_eq!(safe_file_name("v1.4.2"), "v1.4.2");
+        assert_eq!(safe_file_name("1.0-beta+7"), "1.0-beta+7");
+    }
+
+    #[test]
+    fn safe_file_name_replaces_separators() {
+        assert_eq!(safe_file_name("release/1.0"), "release_1.0");
+        assert_eq!(safe_file_name("a\\b:c"), "a_b_c");
+        assert_eq!(
+            safe_file_name("Loader 1.0.exe?x=%20"),
+            "Loader_1.0.exe_x__20"
+        );
+    }
+
+    #[test]
+    fn safe_file_name_never_points_elsewhere() {
+        assert_eq!(safe_file_name(""), "_");
+        assert_eq!(safe_file_name("."), "__");
+        assert_eq!(safe_file_name(".."), "___");
+        assert_eq!(safe_file_name("../x"), ".._x");
+    }
+
+    #[test]
+    fn version_in_name_finds_the_first_number() {
+        assert_eq!(version_in_name("v1.4.2"), [1, 4, 2]);
+        assert_eq!(version_in_name("CollapseLoader-2.10-beta"), [2, 10]);
+        assert_eq!(version_in_name("nightly"), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn version_in_name_compares_numerically() {
+        assert!(version_in_name("v1.10") > version_in_name("v1.9"));
+    }
+
+    /// Creates `versions/<tag>/<name>` under `root` for every `tag/name`.
+    fn install(root: &Path, builds: &[&str]) -> Vec<PathBuf> {
+        builds
+            .iter()
+            .map(|build| {
+                let path = root.join(VERSIONS_DIR).join(build);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(&path, "build").unwrap();
+                path
+            })
+            .collect()
+    }
+
+    #[test]
+    fn delete_old_removes_all_but_the_kept_builds() {
+        let root = tempfile::tempdir().unwrap();
+        let builds = install(root.path(), &["v1.0/a.zip", "v1.1/a.zip", "v1.2/a.zip"]);
+
+        delete_old(root.path(), &builds[1..], true).unwrap();
+        assert!(builds.iter().all(|build| build.exists()));
+
+        delete_old(root.path(), &builds[1..], false).unwrap();
+        assert!(!builds[0].parent().unwrap().exists());
+        assert!(builds[1].exists() && builds[2].exists());
+    }
+
+    #[test]
+    fn delete_old_spares_the_directory_of_a_kept_build() {
+        let root = tempfile::tempdir().unwrap();
+        let builds = install(root.path(), &["v1.0/a.zip", "v1.0/a.tar.gz"]);
+
+        delete_old(root.path(), &builds[..1], false).unwrap();
+        assert!(builds.iter().all(|build| build.exists()));
+    }
+}
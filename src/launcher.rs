@@ -0,0 +1,213 @@
+use std::{
+    io::{self, Write},
+    path::Path,
+    process::{self, ExitStatus, Stdio},
+};
+
+use clap::ValueEnum;
+use console::style;
+use serde::Deserialize;
+use tokio::process::Child;
+
+use crate::{
+    config::Config,
+    install::EntryPoint,
+    output::{self, say, Event},
+    self_update::RELAUNCHED_ENV,
+    UpdaterError,
+};
+
+/// What the updater does once the loader is started.
+#[derive(Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LaunchMode {
+    /// Wait for the loader and exit with its exit code
+    #[default]
+    Wait,
+    /// Replace the updater process with the loader (detach on Windows)
+    Exec,
+    /// Start the loader in the background and exit right away
+    Detach,
+}
+
+/// Starts the loader with the configured arguments in the configured
+/// [`LaunchMode`], from inside its working directory. When waiting, a
+/// non-zero exit becomes [`UpdaterError::LaunchFailed`] carrying the
+/// loader's exit code, which the updater then exits with.
+pub async fn start_loader(entry: &EntryPoint, config: &Config) -> Result<(), UpdaterError> {
+    let file_path = entry.executable.as_path();
+    say!("{}", style("Starting CollapseLoader...\n").green());
+    output::emit(Event::Launch {
+        file: file_path,
+        args: &config.loader_args,
+    });
+
+    let mut command = command(file_path, &config.loader_args);
+    command.current_dir(&entry.working_dir);
+
+    match config.launch_mode {
+        LaunchMode::Wait => wait(command, file_path).await,
+        LaunchMode::Exec => exec(command, file_path),
+        LaunchMode::Detach => detach(command, file_path),
+    }
+}
+
+fn command(file_path: &Path, args: &[String]) -> process::Command {
+    let mut command = process::Command::new(file_path);
+    command.args(args);
+    // Only meant for the relaunched updater
+    command.env_remove(RELAUNCHED_ENV);
+    command.stdin(Stdio::inherit());
+    command.stderr(Stdio::inherit());
+    // Keep stdout for our own events in JSON mode
+    if output::is_json() {
+        command.stdout(io::stderr());
+    } else {
+        command.stdout(Stdio::inherit());
+    }
+    command
+}
+
+/// Runs the loader as a child, passing on signals sent to the updater.
+async fn wait(command: process::Command, file_path: &Path) -> Result<(), UpdaterError> {
+    let mut child = tokio::process::Command::from(command)
+        .spawn()
+        .map_err(UpdaterError::io(format!(
+            "Failed to start {}",
+            file_path.display()
+        )))?;
+    let status = wait_forwarding_signals(&mut child)
+        .await
+        .map_err(UpdaterError::io(format!(
+            "Failed to wait for {}",
+            file_path.display()
+        )))?;
+
+    let exit_code = exit_code(status);
+    output::emit(Event::Exit { code: exit_code });
+
+    if !status.success() {
+        return Err(UpdaterError::LaunchFailed { exit_code });
+    }
+
+    Ok(())
+}
+
+/// Replaces the updater with the loader, keeping the pid, so only returns
+/// if that fails.
+#[cfg(unix)]
+fn exec(mut command: process::Command, file_path: &Path) -> Result<(), UpdaterError> {
+    use std::os::unix::process::CommandExt;
+
+    let _ = io::stdout().flush();
+    let err = command.exec();
+    Err(UpdaterError::io(format!(
+        "Failed to start {}",
+        file_path.display()
+    ))(err))
+}
+
+/// Windows has no `exec`, the closest is starting the loader on its own and
+/// getting out of the way.
+#[cfg(not(unix))]
+fn exec(command: process::Command, file_path: &Path) -> Result<(), UpdaterError> {
+    detach(command, file_path)
+}
+
+/// Starts the loader outside of the updater's process group (on Windows,
+/// without a console) and returns without waiting for it.
+fn detach(mut command: process::Command, file_path: &Path) -> Result<(), UpdaterError> {
+    command.stdin(Stdio::null());
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+
+        command.process_group(0);
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+
+        const DETACHED_PROCESS: u32 = 0x0000_0008;
+        const CREATE_NEW_PROCESS_GROUP: u32 = 0x0000_0200;
+        command.creation_flags(DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP);
+    }
+
+    let child = command.spawn().map_err(UpdaterError::io(format!(
+        "Failed to start {}",
+        file_path.display()
+    )))?;
+    say!(
+        "{} {}",
+        style("CollapseLoader started in the background, pid").green(),
+        child.id()
+    );
+
+    Ok(())
+}
+
+/// Waits for `child`, relaying SIGTERM to it instead of letting it kill the
+/// updater first. The child shares our process group, so in a terminal
+/// Ctrl+C and SIGHUP already reach it and are only kept from killing the
+/// updater; without one, SIGINT and SIGHUP are passed on as well.
+#[cfg(unix)]
+pub async fn wait_forwarding_signals(child: &mut Child) -> io::Result<ExitStatus> {
+    use std::io::IsTerminal;
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut hangup = signal(SignalKind::hangup())?;
+    let in_terminal = io::stdin().is_terminal();
+
+    loop {
+        let signal = tokio::select! {
+            status = child.wait() => return status,
+            _ = interrupt.recv() => {
+                if in_terminal {
+                    continue;
+                }
+                libc::SIGINT
+            }
+            _ = terminate.recv() => libc::SIGTERM,
+            _ = hangup.recv(), if !in_terminal => libc::SIGHUP,
+        };
+
+        if let Some(pid) = child.id() {
+            // SAFETY: plain kill(2) on our own child's pid, which can't have
+            // been reused since it hasn't been reaped yet
+            unsafe {
+                libc::kill(pid as libc::pid_t, signal);
+            }
+        }
+    }
+}
+
+/// Waits for `child`, ignoring Ctrl+C: the loader shares our console, so
+/// Windows already delivers it there and lets it decide how to exit.
+#[cfg(windows)]
+pub async fn wait_forwarding_signals(child: &mut Child) -> io::Result<ExitStatus> {
+    loop {
+        tokio::select! {
+            status = child.wait() => return status,
+            _ = tokio::signal::ctrl_c() => {}
+        }
+    }
+}
+
+/// The exit code the loader reported, or the shell convention of 128 plus
+/// the signal number if a signal killed it.
+pub fn exit_code(status: ExitStatus) -> Option<i32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+
+        status
+            .code()
+            .or_else(|| status.signal().map(|signal| 128 + signal))
+    }
+    #[cfg(not(unix))]
+    {
+        status.code()
+    }
+}
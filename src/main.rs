@@ -1,188 +1,674 @@
+mod archive;
+mod cache;
+mod cli;
+mod config;
+mod delta;
+mod download;
+mod install;
+mod launcher;
+mod mirror;
+mod output;
+mod platform;
+mod release;
+mod retry;
+mod self_update;
+mod signature;
+mod state;
+mod version;
+
 use std::{
-    cmp::min,
-    env,
     error::Error,
-    fmt,
-    fs::{self, File},
-    io::{self, Write},
-    path::Path,
-    process::Command,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    process,
+    time::SystemTime,
 };
 
+use clap::Parser;
+use cli::{Cli, Commands};
+use config::Config;
 use console::style;
-use futures::stream::StreamExt;
-use indicatif::{ProgressBar, ProgressStyle};
+use install::{delete_old, is_file_already_downloaded, newest_local_build, safe_file_name};
+use launcher::{start_loader, LaunchMode};
+use output::{say, Event};
+use release::{get_expected_checksum, get_release, get_releases, select_asset, Asset, Release};
 use reqwest::Client;
-use serde::Deserialize;
-
-#[derive(Deserialize)]
-struct Release {
-    assets: Vec<Asset>,
-    prerelease: bool,
-}
-
-#[derive(Deserialize)]
-struct Asset {
-    browser_download_url: String,
-    size: u64,
-}
+use state::State;
 
+/// Everything that can make the updater fail. Each variant maps to a process
+/// exit code (see [`UpdaterError::exit_code`]) that scripts can rely on.
 #[derive(Debug)]
 enum UpdaterError {
-    ApiRequestError(String),
-    FileOperationError(String),
-    CommandExecutionError(String),
+    /// No usable response: DNS, TLS, proxy, timeouts, dropped connections.
+    Network {
+        context: String,
+        source: reqwest::Error,
+    },
+    /// The server answered with an error status.
+    HttpStatus {
+        code: u16,
+        body: String,
+    },
+    /// The server answered, but not with anything we can use.
+    InvalidResponse(String),
+    Json {
+        context: String,
+        source: serde_json::Error,
+    },
+    Io {
+        context: String,
+        source: io::Error,
+    },
+    Config(String),
+    RateLimited {
+        reset_at: Option<SystemTime>,
+    },
+    IncompleteDownload {
+        received: u64,
+        expected: u64,
+    },
     NoPreReleaseFound,
+    NothingToRollBack,
+    NoLocalBuild,
+    NoMatchingAsset {
+        pattern: Option<String>,
+        candidates: Vec<String>,
+    },
+    /// An archived build has nothing to start, or not what its manifest or
+    /// the entry pattern names.
+    NoEntryPoint {
+        archive: PathBuf,
+        entry: Option<String>,
+    },
+    ChecksumMismatch {
+        expected: String,
+        actual: String,
+    },
+    SignatureInvalid(String),
+    /// The loader ran but exited unsuccessfully. `None` if it was killed by
+    /// a signal.
+    LaunchFailed {
+        exit_code: Option<i32>,
+    },
 }
 
 impl fmt::Display for UpdaterError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            UpdaterError::ApiRequestError(msg) => write!(f, "API request error: {}", msg),
-            UpdaterError::FileOperationError(msg) => write!(f, "File operation error: {}", msg),
-            UpdaterError::CommandExecutionError(msg) => {
-                write!(f, "Command execution error: {}", msg)
+            UpdaterError::Network { context, .. }
+            | UpdaterError::Json { context, .. }
+            | UpdaterError::Io { context, .. } => write!(f, "{}", context),
+            UpdaterError::HttpStatus { code, body } => {
+                write!(f, "Server responded with status {}", code)?;
+                match body.trim() {
+                    "" => Ok(()),
+                    body => write!(f, ": {}", body),
+                }
             }
+            UpdaterError::InvalidResponse(msg) => write!(f, "Invalid response: {}", msg),
+            UpdaterError::Config(msg) => write!(f, "Configuration error: {}", msg),
             UpdaterError::NoPreReleaseFound => write!(f, "No pre-release found!"),
+            UpdaterError::RateLimited { reset_at } => {
+                write!(f, "GitHub API rate limit exceeded")?;
+                match reset_at.and_then(|reset| reset.duration_since(SystemTime::now()).ok()) {
+                    Some(wait) => {
+                        write!(f, ", resets in {} min", wait.as_secs().div_ceil(60).max(1))
+                    }
+                    None => Ok(()),
+                }
+            }
+            UpdaterError::IncompleteDownload { received, expected } => write!(
+                f,
+                "Connection closed after {} of {} bytes",
+                received, expected
+            ),
+            UpdaterError::NoLocalBuild => write!(f, "No local CollapseLoader build found"),
+            UpdaterError::NothingToRollBack => {
+                write!(f, "No previously installed build to roll back to")
+            }
+            UpdaterError::NoMatchingAsset {
+                pattern,
+                candidates,
+            } => write!(
+                f,
+                "No release asset matches {}. Available assets: {}",
+                pattern.as_deref().unwrap_or("this platform"),
+                if candidates.is_empty() {
+                    "none".to_string()
+                } else {
+                    candidates.join(", ")
+                }
+            ),
+            UpdaterError::NoEntryPoint { archive, entry } => write!(
+                f,
+                "No {} found in {}",
+                match entry {
+                    Some(entry) => format!("executable matching {}", entry),
+                    None => "CollapseLoader executable".to_string(),
+                },
+                archive.display()
+            ),
+            UpdaterError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "Checksum mismatch: expected {}, got {}",
+                expected, actual
+            ),
+            UpdaterError::SignatureInvalid(msg) => write!(f, "Invalid signature: {}", msg),
+            UpdaterError::LaunchFailed {
+                exit_code: Some(code),
+            } => write!(f, "CollapseLoader exited with code {}", code),
+            UpdaterError::LaunchFailed { exit_code: None } => {
+                write!(f, "CollapseLoader was terminated")
+            }
         }
     }
 }
 
-impl Error for UpdaterError {}
+impl Error for UpdaterError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            UpdaterError::Network { source, .. } => Some(source),
+            UpdaterError::Json { source, .. } => Some(source),
+            UpdaterError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
 
-async fn get_download_url(
-    client: &Client,
-    pre_release: bool,
-) -> Result<(String, u64), UpdaterError> {
-    let url = format!(
-        "https://api.github.com/repos/dest4590/CollapseLoader/releases{}",
-        if pre_release { "" } else { "/latest" }
-    );
+impl UpdaterError {
+    /// Wraps a failed request, for `map_err`.
+    fn network(context: impl Into<String>) -> impl FnOnce(reqwest::Error) -> Self {
+        move |source| UpdaterError::Network {
+            context: context.into(),
+            source,
+        }
+    }
 
-    let response = client
-        .get(&url)
-        .send()
-        .await
-        .map_err(|err| UpdaterError::ApiRequestError(err.to_string()))?;
-
-    if !response.status().is_success() {
-        let error_message = format!(
-            "API request failed with status code: {}. Response body: {}",
-            response.status(),
-            response
-                .text()
-                .await
-                .unwrap_or_else(|_| "Failed to get response body".to_string()),
-        );
-        return Err(UpdaterError::ApiRequestError(error_message));
+    /// Wraps an unparsable response, for `map_err`.
+    fn json(context: impl Into<String>) -> impl FnOnce(serde_json::Error) -> Self {
+        move |source| UpdaterError::Json {
+            context: context.into(),
+            source,
+        }
     }
 
-    if pre_release {
-        let releases: Vec<Release> = response
-            .json()
-            .await
-            .map_err(|err| UpdaterError::ApiRequestError(err.to_string()))?;
+    /// Wraps a file system error, for `map_err`.
+    fn io(context: impl Into<String>) -> impl FnOnce(io::Error) -> Self {
+        move |source| UpdaterError::Io {
+            context: context.into(),
+            source,
+        }
+    }
 
-        for release in releases {
-            if release.prerelease {
-                if let Some(asset) = release.assets.first() {
-                    return Ok((asset.browser_download_url.clone(), asset.size));
-                }
-            }
+    /// Variant name, reported in `--output json` error events.
+    fn kind(&self) -> &'static str {
+        match self {
+            UpdaterError::Network { .. } => "Network",
+            UpdaterError::HttpStatus { .. } => "HttpStatus",
+            UpdaterError::InvalidResponse(_) => "InvalidResponse",
+            UpdaterError::Json { .. } => "Json",
+            UpdaterError::Io { .. } => "Io",
+            UpdaterError::Config(_) => "Config",
+            UpdaterError::RateLimited { .. } => "RateLimited",
+            UpdaterError::IncompleteDownload { .. } => "IncompleteDownload",
+            UpdaterError::NoPreReleaseFound => "NoPreReleaseFound",
+            UpdaterError::NothingToRollBack => "NothingToRollBack",
+            UpdaterError::NoLocalBuild => "NoLocalBuild",
+            UpdaterError::NoMatchingAsset { .. } => "NoMatchingAsset",
+            UpdaterError::NoEntryPoint { .. } => "NoEntryPoint",
+            UpdaterError::ChecksumMismatch { .. } => "ChecksumMismatch",
+            UpdaterError::SignatureInvalid(_) => "SignatureInvalid",
+            UpdaterError::LaunchFailed { .. } => "LaunchFailed",
         }
+    }
 
-        return Err(UpdaterError::NoPreReleaseFound);
-    } else {
-        let release: Release = response
-            .json()
-            .await
-            .map_err(|err| UpdaterError::ApiRequestError(err.to_string()))?;
-
-        match release.assets.first() {
-            Some(asset) => Ok((asset.browser_download_url.clone(), asset.size)),
-            None => Err(UpdaterError::ApiRequestError(
-                "No assets found in the release".to_string(),
-            )),
+    /// Process exit code, as documented in the README. 2 is left to clap
+    /// for invalid arguments, and a failed loader passes on its own code.
+    fn exit_code(&self) -> i32 {
+        match self {
+            UpdaterError::Io { .. } => 1,
+            UpdaterError::Config(_) => 3,
+            UpdaterError::Network { .. } | UpdaterError::IncompleteDownload { .. } => 4,
+            UpdaterError::HttpStatus { .. }
+            | UpdaterError::InvalidResponse(_)
+            | UpdaterError::Json { .. } => 5,
+            UpdaterError::RateLimited { .. } => 6,
+            UpdaterError::NoPreReleaseFound
+            | UpdaterError::NoMatchingAsset { .. }
+            | UpdaterError::NoEntryPoint { .. } => 7,
+            UpdaterError::ChecksumMismatch { .. } => 8,
+            UpdaterError::SignatureInvalid(_) => 9,
+            UpdaterError::NothingToRollBack | UpdaterError::NoLocalBuild => 10,
+            UpdaterError::LaunchFailed { exit_code } => exit_code.unwrap_or(1),
         }
     }
+
+    /// Whether the error came from talking to the release server, in which
+    /// case a local build can still be launched.
+    fn is_remote(&self) -> bool {
+        matches!(
+            self,
+            UpdaterError::Network { .. }
+                | UpdaterError::HttpStatus { .. }
+                | UpdaterError::InvalidResponse(_)
+                | UpdaterError::Json { .. }
+                | UpdaterError::RateLimited { .. }
+                | UpdaterError::IncompleteDownload { .. }
+        )
+    }
 }
 
-fn is_file_already_downloaded(file_path: &str, expected_size: u64) -> bool {
-    if Path::new(file_path).exists() {
-        if let Ok(metadata) = std::fs::metadata(file_path) {
-            if metadata.len() == expected_size {
-                println!(
-                    "{} {}",
-                    style("Latest version already downloaded:").yellow(),
-                    file_path
-                );
-                return true;
-            }
+/// `err` followed by its chain of sources.
+fn report(err: &dyn Error) -> String {
+    let mut message = err.to_string();
+    let mut source = err.source();
+    while let Some(err) = source {
+        message.push_str(&format!(": {}", err));
+        source = err.source();
+    }
+    message
+}
+
+/// Checks the signature of an already installed build, then launches it.
+async fn launch_installed(build: &Path, config: &Config) -> Result<(), UpdaterError> {
+    signature::verify(build, config)?;
+    let entry = install::entry_point(build, false, config.entry_pattern.as_deref())?;
+    start_loader(&entry, config).await
+}
+
+async fn launch_local_build(config: &Config) -> Result<(), UpdaterError> {
+    let build = newest_local_build(&config.install_dir)
+        .map_err(UpdaterError::io("Failed to look for local builds"))?
+        .ok_or(UpdaterError::NoLocalBuild)?;
+
+    say!(
+        "{} {}",
+        style("Offline, launching local build:").yellow(),
+        build.display()
+    );
+    launch_installed(&build, config).await
+}
+
+fn record_install(state: &mut State, build: &Path, tag: &str) {
+    state.record_install(build, tag);
+    if let Err(err) = state.save() {
+        eprintln!("{} {}", style("Failed to save updater state:").red(), err);
+    }
+}
+
+/// `current` plus the `keep` most recently installed builds before it.
+fn builds_to_keep(state: &State, current: &Path, keep: usize) -> Vec<PathBuf> {
+    std::iter::once(current.to_path_buf())
+        .chain(state.builds().filter(|build| build != current).take(keep))
+        .collect()
+}
+
+/// HTTP client honouring the proxy, certificate and timeout settings.
+/// `HTTPS_PROXY`/`NO_PROXY` are picked up by reqwest unless a proxy is
+/// configured explicitly.
+fn build_client(config: &Config) -> Result<Client, UpdaterError> {
+    let mut builder = Client::builder()
+        .user_agent("CollapseUpdater")
+        .connect_timeout(config.connect_timeout)
+        .read_timeout(config.read_timeout);
+
+    if let Some(proxy) = &config.proxy {
+        let proxy = reqwest::Proxy::all(proxy.as_str())
+            .map_err(|err| UpdaterError::Config(format!("Invalid proxy: {}", err)))?
+            .no_proxy(reqwest::NoProxy::from_env());
+        builder = builder.proxy(proxy);
+    }
+
+    for path in &config.ca_certs {
+        let pem = fs::read(path).map_err(|err| {
+            UpdaterError::Config(format!("Failed to read {}: {}", path.display(), err))
+        })?;
+        let certs = reqwest::Certificate::from_pem_bundle(&pem).map_err(|err| {
+            UpdaterError::Config(format!("Invalid certificate {}: {}", path.display(), err))
+        })?;
+        if certs.is_empty() {
+            return Err(UpdaterError::Config(format!(
+                "No certificates found in {}",
+                path.display()
+            )));
+        }
+        for cert in certs {
+            builder = builder.add_root_certificate(cert);
         }
     }
-    false
+
+    builder
+        .build()
+        .map_err(UpdaterError::network("Failed to set up the HTTP client"))
 }
 
-fn delete_old(exclude: &str) -> Result<(), io::Error> {
-    let folder = env::current_dir()?;
-    let entries = fs::read_dir(&folder)?
-        .filter_map(|res| res.ok())
-        .filter(|entry| entry.file_type().map(|ft| ft.is_file()).unwrap_or(false))
-        .filter_map(|entry| entry.file_name().into_string().ok())
-        .filter(|filename| {
-            filename != exclude
-                && filename.starts_with("CollapseLoader")
-                && filename.ends_with(".exe")
-        })
-        .collect::<Vec<String>>();
-
-    for filename in entries {
-        let file_path = folder.join(&filename);
-        match fs::remove_file(&file_path) {
-            Ok(_) => println!("{} {}", style("Deleted:").red(), filename),
-            Err(e) => eprintln!("{} {}: {}", style("Failed to delete").red(), filename, e),
+/// Updates to the configured release and, if `launch` is set, starts it.
+async fn update(config: &Config, state: &mut State, launch: bool) -> Result<(), UpdaterError> {
+    if config.rollback {
+        let current = state.builds().next();
+        let previous = state
+            .previous_install(current.as_deref())
+            .ok_or(UpdaterError::NothingToRollBack)?;
+
+        say!(
+            "{} {}",
+            style("Rolling back to:").yellow(),
+            previous.display()
+        );
+        if launch {
+            launch_installed(&previous, config).await?;
+        }
+        return Ok(());
+    }
+
+    if config.offline {
+        if !launch {
+            return Err(UpdaterError::Config(
+                "--offline can't be used to download updates".to_string(),
+            ));
+        }
+        return launch_local_build(config).await;
+    }
+
+    let client = build_client(config)?;
+    let release = match get_release(&client, config).await {
+        Ok(release) => release,
+        Err(err) if launch && err.is_remote() => {
+            eprintln!(
+                "{} {}",
+                style("Failed to check for updates:").red(),
+                report(&err)
+            );
+            return launch_local_build(config).await;
+        }
+        Err(err) => return Err(err),
+    };
+
+    let remote_version = version::parse(&release.tag_name);
+    let installed_version = state.installed_version.as_deref().and_then(version::parse);
+    if let (Some(installed), Some(remote)) = (&installed_version, &remote_version) {
+        output::emit(Event::VersionCheck {
+            installed: Some(installed.to_string()),
+            remote: Some(remote.to_string()),
+            status: if installed == remote {
+                "up_to_date"
+            } else if installed < remote {
+                "update"
+            } else if config.version.is_some() {
+                "downgrade"
+            } else {
+                "local_newer"
+            },
+        });
+
+        if installed == remote {
+            say!("{} ({})", style("Up to date").green(), remote);
+        } else if installed < remote {
+            say!("{} {} → {}", style("Updating").blue(), installed, remote);
+        } else if config.version.is_some() {
+            say!(
+                "{} {} → {}",
+                style("Downgrading").yellow(),
+                installed,
+                remote
+            );
+        } else if let Some(current) = state.current_install() {
+            say!(
+                "{} ({} > {})",
+                style("Local build is newer than remote").yellow(),
+                installed,
+                remote
+            );
+            if launch {
+                launch_installed(&current, config).await?;
+            }
+            return Ok(());
         }
     }
 
+    let asset = select_asset(&release, config.asset_pattern.as_ref())?;
+    output::emit(Event::ReleaseFound {
+        tag: &release.tag_name,
+        asset: &asset.name,
+        size: asset.size,
+    });
+    let build = config
+        .version_dir(&release.tag_name)
+        .join(safe_file_name(&asset.name));
+
+    let downloaded = match fetch_build(&client, config, state, &release, asset, &build).await {
+        Ok(downloaded) => downloaded,
+        Err(err) if launch && err.is_remote() => {
+            eprintln!(
+                "{} {}",
+                style("Failed to download the update:").red(),
+                report(&err)
+            );
+            return launch_local_build(config).await;
+        }
+        Err(err) => return Err(err),
+    };
+
+    signature::verify(&build, config)?;
+    let entry = install::entry_point(&build, downloaded, config.entry_pattern.as_deref())?;
+    record_install(state, &build, &release.tag_name);
+
+    if !launch {
+        clean_up(config, state, &build);
+        return Ok(());
+    }
+
+    // `exec` doesn't come back, so that is the last chance to clean up
+    if config.launch_mode == LaunchMode::Exec {
+        clean_up(config, state, &build);
+    }
+    start_loader(&entry, config).await?;
+    if config.launch_mode != LaunchMode::Exec {
+        clean_up(config, state, &build);
+    }
+
     Ok(())
 }
 
-fn start_loader(file_path: &str) -> Result<(), UpdaterError> {
-    println!("{}", style("Starting CollapseLoader...\n").green());
+/// Gets `build` and its signature onto disk, from a patch, a mirror or the
+/// release host, unless it is already there. Returns whether it had to be
+/// downloaded.
+async fn fetch_build(
+    client: &Client,
+    config: &Config,
+    state: &State,
+    release: &Release,
+    asset: &Asset,
+    build: &Path,
+) -> Result<bool, UpdaterError> {
+    let download_url = config.asset_url(&asset.browser_download_url)?;
+    let expected_sha256 = get_expected_checksum(client, config, release, asset).await?;
+
+    if let Err(err) = download::delete_stale_parts(&config.install_dir, Some(build)) {
+        eprintln!("{} {}", style("Error deleting files:").red(), err);
+    }
 
-    let full_path = std::env::current_dir().unwrap().join(file_path);
+    let downloaded = !is_file_already_downloaded(build, asset.size, expected_sha256.as_deref());
+    if downloaded
+        && !delta::update_from_patch(
+            client,
+            config,
+            state,
+            release,
+            asset,
+            build,
+            expected_sha256.as_deref(),
+        )
+        .await
+    {
+        say!(
+            "{} {}",
+            style(format!("\nDownloading release {}:", release.tag_name)).blue(),
+            build.display()
+        );
 
-    let mut command = Command::new(full_path);
+        mirror::download_with_mirrors(
+            client,
+            config,
+            release,
+            asset,
+            &download_url,
+            build,
+            expected_sha256.as_deref(),
+        )
+        .await?;
+    }
 
-    for arg in std::env::args().skip(1) {
-        command.arg(arg);
+    // A build that is already installed keeps the signature saved with it
+    if downloaded || !signature::signature_path(build).is_file() {
+        signature::fetch_signature(client, config, release, asset, build).await?;
     }
 
-    command.stdin(std::process::Stdio::inherit());
-    command.stdout(std::process::Stdio::inherit());
-    command.stderr(std::process::Stdio::inherit());
+    Ok(downloaded)
+}
 
-    let output = command
-        .output()
-        .map_err(|err| UpdaterError::CommandExecutionError(err.to_string()))?;
+/// Deletes builds other than `current` and the `config.keep` installed
+/// before it. Only done once `current` is verified and, when launching,
+/// has started fine, so a failed update never leaves nothing to run.
+fn clean_up(config: &Config, state: &State, current: &Path) {
+    let keep = builds_to_keep(state, current, config.keep);
+    if let Err(err) = delete_old(&config.install_dir, &keep, false) {
+        eprintln!("{} {}", style("Error deleting files:").red(), err);
+    }
+}
 
-    if !output.status.success() {
-        return Err(UpdaterError::CommandExecutionError(format!(
-            "Process exited with code: {}",
-            output.status
-        )));
+/// Reports whether an update is available without downloading anything.
+async fn check(config: &Config, state: &State) -> Result<(), UpdaterError> {
+    let client = build_client(config)?;
+    let release = get_release(&client, config).await?;
+    let asset = select_asset(&release, config.asset_pattern.as_ref())?;
+    output::emit(Event::ReleaseFound {
+        tag: &release.tag_name,
+        asset: &asset.name,
+        size: asset.size,
+    });
+
+    let remote_version = version::parse(&release.tag_name);
+    let installed_version = state.installed_version.as_deref().and_then(version::parse);
+    output::emit(Event::VersionCheck {
+        installed: installed_version.as_ref().map(ToString::to_string),
+        remote: remote_version.as_ref().map(ToString::to_string),
+        status: match (&installed_version, &remote_version) {
+            (Some(installed), Some(remote)) if installed == remote => "up_to_date",
+            (Some(installed), Some(remote)) if installed < remote => "update",
+            (Some(_), Some(_)) => "local_newer",
+            _ => "unknown",
+        },
+    });
+
+    match (&installed_version, &remote_version) {
+        (Some(installed), Some(remote)) if installed == remote => {
+            say!("{} ({})", style("Up to date").green(), remote);
+        }
+        (Some(installed), Some(remote)) if installed < remote => {
+            say!(
+                "{} {} → {}",
+                style("Update available:").blue(),
+                installed,
+                remote
+            );
+        }
+        (Some(installed), Some(remote)) => {
+            say!(
+                "{} ({} > {})",
+                style("Local build is newer than remote").yellow(),
+                installed,
+                remote
+            );
+        }
+        _ => say!("{} {}", style("Latest release:").blue(), release.tag_name),
     }
+    say!("{} {}", style("Asset:").blue(), asset.name);
 
     Ok(())
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    let pre_release = std::env::args().any(|arg| arg == "--prerelease");
+/// Launches the installed build without checking for updates.
+async fn launch(config: &Config, state: &State) -> Result<(), UpdaterError> {
+    match state.current_install() {
+        Some(current) => launch_installed(&current, config).await,
+        None => launch_local_build(config).await,
+    }
+}
+
+async fn list(config: &Config, state: &State) -> Result<(), UpdaterError> {
+    let client = build_client(config)?;
+    let releases = get_releases(&client, config).await?;
+
+    for release in releases {
+        let published = release
+            .published_at
+            .as_deref()
+            .and_then(|date| date.get(..10))
+            .unwrap_or("");
+        let installed = state.installed_version.as_deref() == Some(release.tag_name.as_str());
+
+        if output::is_json() {
+            output::emit(Event::ReleaseListed {
+                tag: &release.tag_name,
+                published_at: release.published_at.as_deref(),
+                prerelease: release.prerelease,
+                installed,
+            });
+            continue;
+        }
+
+        say!(
+            "{:<16} {:<10} {}{}",
+            style(&release.tag_name).bold(),
+            published,
+            if release.prerelease {
+                style("pre-release ").yellow().to_string()
+            } else {
+                String::new()
+            },
+            if installed {
+                style("(installed)").green().to_string()
+            } else {
+                String::new()
+            }
+        );
+    }
+
+    Ok(())
+}
+
+/// Deletes old builds, keeping the installed one and `config.keep` before it.
+fn clean(config: &Config, state: &State, dry_run: bool) -> Result<(), UpdaterError> {
+    let current = match state.current_install() {
+        Some(current) => current,
+        None => match newest_local_build(&config.install_dir)
+            .map_err(UpdaterError::io("Failed to look for local builds"))?
+        {
+            Some(newest) => newest,
+            None => return Ok(()),
+        },
+    };
+
+    delete_old(
+        &config.install_dir,
+        &builds_to_keep(state, &current, config.keep),
+        dry_run,
+    )
+    .map_err(UpdaterError::io("Failed to delete old builds"))
+}
 
-    let client = Client::builder().user_agent("CollapseUpdater").build()?;
-    let (download_url, total_size) = get_download_url(&client, pre_release).await?;
-    let filename = download_url[download_url.rfind('/').unwrap_or(0) + 1..].to_string();
+async fn update_self(config: &Config) -> Result<(), UpdaterError> {
+    let client = build_client(config)?;
+    self_update::run(&client, config).await
+}
+
+async fn run(cli: Cli) -> Result<(), UpdaterError> {
+    let mut loader_args = cli.loader_args;
+    if let Some(Commands::Launch { loader_args: args }) = &cli.command {
+        loader_args.extend(args.iter().cloned());
+    }
+    let config = Config::load(&cli.options, loader_args)?;
 
     let panel_width = 40;
     let welcome_text = format!(
@@ -197,70 +683,151 @@ async fn main() -> Result<(), Box<dyn Error>> {
         "─".repeat(panel_width - 2),
         width = panel_width - 2
     );
-    print!("{}", welcome_text);
+    if !output::is_json() {
+        print!("{}", welcome_text);
+    }
 
-    if let Err(err) = delete_old(&filename) {
+    fs::create_dir_all(&config.install_dir).map_err(UpdaterError::io(format!(
+        "Failed to create {}",
+        config.install_dir.display()
+    )))?;
+    if let Err(err) = download::delete_stale_parts(&config.install_dir, None) {
         eprintln!("{} {}", style("Error deleting files:").red(), err);
     }
+    self_update::delete_stale();
 
-    if is_file_already_downloaded(&filename, total_size) {
-        start_loader(&filename)?;
-        return Ok(());
+    if matches!(cli.command, None | Some(Commands::Update)) {
+        if let Err(err) = update_self(&config).await {
+            eprintln!("{} {}", style("Self-update failed:").yellow(), report(&err));
+        }
     }
 
-    println!(
-        "{} {}",
-        style("\nDownloading latest release:").blue(),
-        filename
-    );
-
-    let res = client
-        .get(&download_url)
-        .send()
-        .await
-        .map_err(|err| UpdaterError::ApiRequestError(err.to_string()))?;
-
-    let pb = ProgressBar::new(total_size);
-    pb.set_style(
-        ProgressStyle::default_bar()
-            .template("{msg}\n{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})")
-            .unwrap()
-            .progress_chars("#>-"),
-    );
-
-    pb.set_message("Downloading...");
+    let mut state = State::load(&config.install_dir);
+    if let Err(err) = install::migrate_flat_builds(&config.install_dir, &mut state) {
+        eprintln!("{} {}", style("Error moving old builds:").red(), err);
+    }
 
-    let mut downloaded: u64 = 0;
-    let mut file = File::create(&filename).map_err(|err| {
-        UpdaterError::FileOperationError(format!("Failed to create file: {}", err))
-    })?;
+    match cli.command {
+        None => update(&config, &mut state, true).await,
+        Some(Commands::Update) => update(&config, &mut state, false).await,
+        Some(Commands::Check) => check(&config, &state).await,
+        Some(Commands::Launch { .. }) => launch(&config, &state).await,
+        Some(Commands::List) => list(&config, &state).await,
+        Some(Commands::Clean { dry_run }) => clean(&config, &state, dry_run),
+    }
+}
 
-    let mut stream = res.bytes_stream();
-    while let Some(item) = stream.next().await {
-        let chunk = item.map_err(|err| {
-            UpdaterError::ApiRequestError(format!("Error downloading file: {}", err))
-        })?;
-        file.write_all(&chunk).map_err(|err| {
-            UpdaterError::FileOperationError(format!("Error writing to file: {}", err))
-        })?;
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+    output::init(cli.options.output);
+
+    if let Err(err) = run(cli).await {
+        let message = report(&err);
+        eprintln!("{} {}", style("Error:").red().bold(), message);
+        output::emit(Event::Error {
+            kind: err.kind(),
+            code: err.exit_code(),
+            message,
+        });
+        process::exit(err.exit_code());
+    }
+}
 
-        let new = min(downloaded + (chunk.len() as u64), total_size);
-        downloaded = new;
-        pb.set_position(downloaded);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_codes_match_the_readme() {
+        let io = UpdaterError::io("Failed to write")(io::Error::other("disk full"));
+        let json =
+            UpdaterError::json("Invalid release")(serde_json::from_str::<u8>("x").unwrap_err());
+        let cases = [
+            (io, 1),
+            (UpdaterError::Config("bad".to_string()), 3),
+            (
+                UpdaterError::IncompleteDownload {
+                    received: 1,
+                    expected: 2,
+                },
+                4,
+            ),
+            (
+                UpdaterError::HttpStatus {
+                    code: 404,
+                    body: String::new(),
+                },
+                5,
+            ),
+            (UpdaterError::InvalidResponse("empty".to_string()), 5),
+            (json, 5),
+            (UpdaterError::RateLimited { reset_at: None }, 6),
+            (UpdaterError::NoPreReleaseFound, 7),
+            (
+                UpdaterError::NoMatchingAsset {
+                    pattern: None,
+                    candidates: Vec::new(),
+                },
+                7,
+            ),
+            (
+                UpdaterError::NoEntryPoint {
+                    archive: PathBuf::from("bundle.zip"),
+                    entry: None,
+                },
+                7,
+            ),
+            (
+                UpdaterError::ChecksumMismatch {
+                    expected: "a".to_string(),
+                    actual: "b".to_string(),
+                },
+                8,
+            ),
+            (UpdaterError::SignatureInvalid("unsigned".to_string()), 9),
+            (UpdaterError::NothingToRollBack, 10),
+            (UpdaterError::NoLocalBuild, 10),
+        ];
+
+        for (err, code) in cases {
+            assert_eq!(err.exit_code(), code, "{}", err.kind());
+        }
     }
 
-    pb.finish_with_message(format!(
-        "{} {}",
-        style("Downloaded successfully:").green().bold(),
-        filename
-    ));
+    #[test]
+    fn failed_loader_passes_its_exit_code_on() {
+        let exited = UpdaterError::LaunchFailed {
+            exit_code: Some(42),
+        };
+        let killed = UpdaterError::LaunchFailed { exit_code: None };
+        assert_eq!(exited.exit_code(), 42);
+        assert_eq!(killed.exit_code(), 1);
+    }
 
-    drop(file);
-    drop(stream);
+    #[test]
+    fn keeps_the_current_build_and_the_latest_before_it() {
+        let root = tempfile::tempdir().unwrap();
+        let mut state = State::load(root.path());
+        let build = |tag: &str| root.path().join(tag).join("a.exe");
+        for tag in ["v1.0", "v1.1", "v1.2"] {
+            state.record_install(&build(tag), tag);
+        }
 
-    if let Err(err) = start_loader(&filename) {
-        eprintln!("Error: {}", err);
+        assert_eq!(
+            builds_to_keep(&state, &build("v1.3"), 1),
+            [build("v1.3"), build("v1.2")]
+        );
+        assert_eq!(
+            builds_to_keep(&state, &build("v1.2"), 1),
+            [build("v1.2"), build("v1.1")]
+        );
+        assert_eq!(builds_to_keep(&state, &build("v1.2"), 0), [build("v1.2")]);
     }
 
-    Ok(())
+    #[test]
+    fn report_follows_the_sources() {
+        let err = UpdaterError::io("Failed to write")(io::Error::other("disk full"));
+        assert_eq!(report(&err), "Failed to write: disk full");
+    }
 }
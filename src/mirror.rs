@@ -0,0 +1,139 @@
+use std::{fs, path::Path};
+
+use console::style;
+use reqwest::{Client, Url};
+
+use crate::{
+    config::Config,
+    download,
+    output::{self, say, Event},
+    release::{check_status, Asset, Release},
+    UpdaterError,
+};
+
+/// Manifest every mirror serves at its base URL, listing releases in the
+/// same format as the GitHub releases API. Asset URLs may be relative to
+/// the mirror.
+pub const MANIFEST_NAME: &str = "releases.json";
+
+/// Downloads `asset` from the configured mirrors in order and then from
+/// `download_url`, stopping at the first source that delivers a file
+/// matching the canonical checksum.
+pub async fn download_with_mirrors(
+    client: &Client,
+    config: &Config,
+    release: &Release,
+    asset: &Asset,
+    download_url: &str,
+    file: &Path,
+    expected_sha256: Option<&str>,
+) -> Result<(), UpdaterError> {
+    let mirrors = match expected_sha256 {
+        Some(_) => config.mirrors.as_slice(),
+        // Without a checksum from the release there is nothing to check
+        // mirrored files against
+        None => {
+            if !config.mirrors.is_empty() {
+                eprintln!(
+                    "{}",
+                    style("No checksum published, not using mirrors").yellow()
+                );
+            }
+            &[]
+        }
+    };
+
+    let mut last_error = None;
+    for mirror in mirrors.iter().map(Some).chain([None]) {
+        // Mirrors are only looked up once the ones before them have failed
+        let url = match mirror {
+            Some(mirror) => match mirror_url(client, config, mirror, release, asset).await {
+                Ok(url) => url,
+                Err(err) => {
+                    eprintln!("{} {}: {}", style("Skipping mirror").yellow(), mirror, err);
+                    continue;
+                }
+            },
+            None => download_url.to_string(),
+        };
+        let source = mirror.map_or("release host", Url::as_str);
+
+        let result = download::download(
+            client,
+            &url,
+            file,
+            asset.size,
+            expected_sha256,
+            &config.retry,
+        )
+        .await;
+
+        match result {
+            Ok(()) => {
+                say!("{} {}", style("Served by:").blue(), source);
+                output::emit(Event::Downloaded { file, source: &url });
+                return Ok(());
+            }
+            Err(err) => {
+                eprintln!(
+                    "{} {}: {}",
+                    style("Download failed from").red(),
+                    source,
+                    err
+                );
+                // Whatever this mirror left behind must not be resumed from
+                // the next source
+                if mirror.is_some() {
+                    let _ = fs::remove_file(download::part_path(file));
+                }
+                last_error = Some(err);
+            }
+        }
+    }
+
+    Err(last_error.expect("the release host is always tried"))
+}
+
+/// Finds `asset` of `release` in the manifest of `mirror`.
+async fn mirror_url(
+    client: &Client,
+    config: &Config,
+    mirror: &Url,
+    release: &Release,
+    asset: &Asset,
+) -> Result<String, UpdaterError> {
+    let manifest_url = mirror
+        .join(MANIFEST_NAME)
+        .map_err(|err| UpdaterError::Config(err.to_string()))?;
+
+    let response = config
+        .retry
+        .send(|| client.get(manifest_url.clone()))
+        .await
+        .map_err(UpdaterError::network("Failed to fetch mirror manifest"))?;
+    let body = check_status(response)
+        .await?
+        .text()
+        .await
+        .map_err(UpdaterError::network("Failed to read mirror manifest"))?;
+    let releases: Vec<Release> = serde_json::from_str(&body).map_err(UpdaterError::json(
+        format!("Invalid manifest {}", manifest_url),
+    ))?;
+
+    let mirrored = releases
+        .iter()
+        .filter(|mirrored| mirrored.tag_name == release.tag_name)
+        .flat_map(|mirrored| &mirrored.assets)
+        .find(|mirrored| mirrored.name == asset.name)
+        .ok_or_else(|| {
+            UpdaterError::InvalidResponse(format!(
+                "{} {} is not mirrored",
+                release.tag_name, asset.name
+            ))
+        })?;
+
+    mirror
+        .join(&mirrored.browser_download_url)
+        .map(String::from)
+        .map_err(|err| UpdaterError::Config(err.to_string()))
+}
@@ -0,0 +1,135 @@
+use std::{
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use clap::ValueEnum;
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::Serialize;
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Colored, human readable output
+    Text,
+    /// One JSON event per line on stdout
+    Json,
+}
+
+/// Something that happened during a run, printed as one NDJSON line in
+/// `--output json` mode.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    ReleaseFound {
+        tag: &'a str,
+        asset: &'a str,
+        size: u64,
+    },
+    VersionCheck {
+        installed: Option<String>,
+        remote: Option<String>,
+        status: &'a str,
+    },
+    ReleaseListed {
+        tag: &'a str,
+        published_at: Option<&'a str>,
+        prerelease: bool,
+        installed: bool,
+    },
+    DownloadStarted {
+        file: &'a Path,
+        size: u64,
+        offset: u64,
+    },
+    DownloadProgress {
+        file: &'a Path,
+        downloaded: u64,
+        size: u64,
+    },
+    Downloaded {
+        file: &'a Path,
+        source: &'a str,
+    },
+    Verification {
+        file: &'a Path,
+        expected: Option<&'a str>,
+        actual: &'a str,
+        ok: bool,
+    },
+    Signature {
+        file: &'a Path,
+        ok: bool,
+    },
+    Deleted {
+        file: &'a Path,
+    },
+    WouldDelete {
+        file: &'a Path,
+    },
+    Launch {
+        file: &'a Path,
+        args: &'a [String],
+    },
+    Exit {
+        code: Option<i32>,
+    },
+    Error {
+        kind: &'a str,
+        code: i32,
+        message: String,
+    },
+}
+
+pub fn init(format: OutputFormat) {
+    if format == OutputFormat::Json {
+        JSON.store(true, Ordering::Relaxed);
+        console::set_colors_enabled(false);
+        console::set_colors_enabled_stderr(false);
+    }
+}
+
+pub fn is_json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// Prints `event` to stdout in JSON mode, does nothing otherwise.
+pub fn emit(event: Event) {
+    if is_json() {
+        if let Ok(line) = serde_json::to_string(&event) {
+            println!("{}", line);
+        }
+    }
+}
+
+/// The download progress bar, hidden in JSON mode where progress is
+/// reported through [`Event::DownloadProgress`] instead.
+pub fn progress_bar(size: u64) -> ProgressBar {
+    if is_json() {
+        return ProgressBar::hidden();
+    }
+
+    let pb = ProgressBar::new(size);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("{msg}\n{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})")
+            .unwrap()
+            .progress_chars("#>-"),
+    );
+    pb
+}
+
+/// Prints a human readable status line. In JSON mode it goes to stderr so
+/// that stdout only carries events.
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if $crate::output::is_json() {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+pub(crate) use say;
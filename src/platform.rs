@@ -0,0 +1,127 @@
+//! Naming conventions for release assets on each supported platform, and
+//! what it takes to run them.
+
+use std::{io, path::Path};
+
+/// Substrings (lowercase) that mark an asset as built for the current OS.
+pub const OS_HINTS: &[&str] = if cfg!(target_os = "windows") {
+    &[".exe", "windows", "win64", "win32"]
+} else if cfg!(target_os = "macos") {
+    &["macos", "darwin", "osx"]
+} else {
+    &["linux", ".appimage"]
+};
+
+/// Asset formats (lowercase suffixes) in order of preference when a release
+/// has several builds for the current OS.
+pub const FORMATS: &[&str] = if cfg!(target_os = "windows") {
+    &[".exe", ".zip", ".tar.gz", ".tgz"]
+} else if cfg!(target_os = "macos") {
+    &[".tar.gz", ".tgz", ".zip"]
+} else {
+    &[".appimage", ".tar.gz", ".tgz", ".zip"]
+};
+
+/// Substrings (lowercase) that mark an asset as built for the current CPU.
+pub const ARCH_HINTS: &[&str] = if cfg!(target_arch = "x86_64") {
+    &["x86_64", "x86-64", "x64", "amd64", "win64"]
+} else if cfg!(target_arch = "aarch64") {
+    &["aarch64", "arm64"]
+} else if cfg!(target_arch = "x86") {
+    &["i686", "i386", "x86", "win32"]
+} else {
+    &[]
+};
+
+/// Architecture markers of every platform we know about, used to tell a
+/// build for another CPU apart from an architecture-neutral asset name.
+const ALL_ARCH_HINTS: &[&str] = &[
+    "x86_64", "x86-64", "x64", "amd64", "win64", "aarch64", "arm64", "i686", "i386", "x86", "win32",
+];
+
+pub fn matches_os(name: &str) -> bool {
+    let name = name.to_lowercase();
+    OS_HINTS.iter().any(|hint| name.contains(hint))
+}
+
+pub fn matches_arch(name: &str) -> bool {
+    let name = name.to_lowercase();
+    ARCH_HINTS.iter().any(|hint| contains_arch(&name, hint))
+}
+
+pub fn mentions_any_arch(name: &str) -> bool {
+    let name = name.to_lowercase();
+    ALL_ARCH_HINTS.iter().any(|hint| contains_arch(&name, hint))
+}
+
+/// Whether `hint` appears in `name` on its own, so that `x86` doesn't
+/// match the start of `x86_64`.
+fn contains_arch(name: &str, hint: &str) -> bool {
+    name.match_indices(hint).any(|(start, _)| {
+        let rest = &name[start + hint.len()..];
+        !rest.starts_with("_64") && !rest.starts_with("-64")
+    })
+}
+
+/// Position of the asset's format in [`FORMATS`], unknown formats last.
+pub fn format_rank(name: &str) -> usize {
+    let name = name.to_lowercase();
+    FORMATS
+        .iter()
+        .position(|format| name.ends_with(format))
+        .unwrap_or(FORMATS.len())
+}
+
+/// Whether `path` can be started directly: an `.exe` on Windows, a file
+/// with an executable bit elsewhere.
+pub fn is_runnable(path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        std::fs::metadata(path).is_ok_and(|metadata| metadata.permissions().mode() & 0o111 != 0)
+    }
+    #[cfg(not(unix))]
+    {
+        path.extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("exe"))
+    }
+}
+
+/// Sets the executable bits of a downloaded build for everyone who can read
+/// it. Windows goes by the extension instead.
+pub fn make_executable(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut permissions = std::fs::metadata(path)?.permissions();
+        let mode = permissions.mode();
+        permissions.set_mode(mode | (mode & 0o444) >> 2);
+        std::fs::set_permissions(path, permissions)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn x86_does_not_match_x86_64() {
+        assert!(!contains_arch("collapseloader-x86_64.exe", "x86"));
+        assert!(!contains_arch("collapseloader-x86-64.exe", "x86"));
+        assert!(contains_arch("collapseloader-x86.exe", "x86"));
+        assert!(contains_arch("collapseloader-x86_64.exe", "x86_64"));
+    }
+
+    #[test]
+    fn unknown_formats_rank_last() {
+        assert_eq!(format_rank("CollapseLoader.dmg"), FORMATS.len());
+        assert!(format_rank("CollapseLoader.TAR.GZ") < FORMATS.len());
+    }
+}
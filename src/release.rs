@@ -0,0 +1,482 @@
+use std::{
+    path::Path,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use console::style;
+use glob::{MatchOptions, Pattern};
+use reqwest::{header, Client, Response, StatusCode};
+use serde::Deserialize;
+
+use crate::{
+    archive,
+    cache::{unix_now, CachedResponse, ReleaseCache},
+    config::Config,
+    delta,
+    output::say,
+    platform,
+    signature::SIGNATURE_SUFFIXES,
+    UpdaterError,
+};
+
+pub const CHECKSUM_SUFFIX: &str = ".sha256";
+
+/// Warn once fewer than this many API requests are left in the window.
+const RATE_LIMIT_WARNING: u64 = 5;
+
+#[derive(Deserialize)]
+pub struct Release {
+    pub tag_name: String,
+    pub assets: Vec<Asset>,
+    pub prerelease: bool,
+    #[serde(default)]
+    pub published_at: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct Asset {
+    pub name: String,
+    pub browser_download_url: String,
+    pub size: u64,
+    #[serde(default)]
+    pub content_type: Option<String>,
+    #[serde(default)]
+    pub digest: Option<String>,
+}
+
+pub async fn get_release(client: &Client, config: &Config) -> Result<Release, UpdaterError> {
+    let list_releases = config.version.is_none() && config.pre_release;
+    let url = match &config.version {
+        Some(tag) => format!("{}/tags/{}", config.releases_url(), tag),
+        None if list_releases => config.releases_url(),
+        None => format!("{}/latest", config.releases_url()),
+    };
+
+    let body = fetch_cached(client, config, &url).await?;
+
+    if list_releases {
+        let releases: Vec<Release> =
+            serde_json::from_str(&body).map_err(UpdaterError::json("Invalid release list"))?;
+
+        releases
+            .into_iter()
+            .find(|release| {
+                release.prerelease && select_asset(release, config.asset_pattern.as_ref()).is_ok()
+            })
+            .ok_or(UpdaterError::NoPreReleaseFound)
+    } else {
+        serde_json::from_str(&body).map_err(UpdaterError::json("Invalid release"))
+    }
+}
+
+/// All releases of the configured repository, newest first.
+pub async fn get_releases(client: &Client, config: &Config) -> Result<Vec<Release>, UpdaterError> {
+    let body = fetch_cached(client, config, &config.releases_url()).await?;
+    serde_json::from_str(&body).map_err(UpdaterError::json("Invalid release list"))
+}
+
+/// The latest release of the updater itself.
+pub async fn get_updater_release(
+    client: &Client,
+    config: &Config,
+) -> Result<Release, UpdaterError> {
+    let url = format!("{}/latest", config.updater_releases_url());
+    let body = fetch_cached(client, config, &url).await?;
+    serde_json::from_str(&body).map_err(UpdaterError::json("Invalid updater release"))
+}
+
+/// Returns the body of an API response, reusing the cached copy when it is
+/// still fresh, unchanged on the server, or the API is rate limited.
+async fn fetch_cached(client: &Client, config: &Config, url: &str) -> Result<String, UpdaterError> {
+    let mut cache = ReleaseCache::load(&config.install_dir);
+    let body = match cache.get(url) {
+        Some(cached) if cached.is_fresh(config.check_interval) => cached.body.clone(),
+        cached => match fetch_api(client, config, url, cached).await {
+            Ok(Some(response)) => {
+                let body = response.body.clone();
+                cache.insert(url, response);
+                save_cache(&cache, config);
+                body
+            }
+            Ok(None) => {
+                let Some(cached) = cache.get_mut(url) else {
+                    return Err(UpdaterError::InvalidResponse(
+                        "Unexpected 304 Not Modified response".to_string(),
+                    ));
+                };
+                cached.checked_at = unix_now();
+                let body = cached.body.clone();
+                save_cache(&cache, config);
+                body
+            }
+            Err(err @ UpdaterError::RateLimited { .. }) => match cache.get(url) {
+                Some(cached) => {
+                    eprintln!("{} {}", style("Using cached release info:").yellow(), err);
+                    cached.body.clone()
+                }
+                None => return Err(err),
+            },
+            Err(err) => return Err(err),
+        },
+    };
+
+    Ok(body)
+}
+
+fn save_cache(cache: &ReleaseCache, config: &Config) {
+    if let Err(err) = cache.save(&config.install_dir) {
+        eprintln!("{} {}", style("Failed to save release cache:").red(), err);
+    }
+}
+
+/// Sends an authenticated API request, made conditional on `cached` when
+/// there is one. Returns `None` if the server answered `304 Not Modified`.
+async fn fetch_api(
+    client: &Client,
+    config: &Config,
+    url: &str,
+    cached: Option<&CachedResponse>,
+) -> Result<Option<CachedResponse>, UpdaterError> {
+    let build_request = || {
+        let mut request = client
+            .get(url)
+            .header(header::ACCEPT, "application/vnd.github+json");
+        if let Some(token) = &config.token {
+            request = request.bearer_auth(token);
+        }
+        if let Some(etag) = cached.and_then(|cached| cached.etag.as_deref()) {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = cached.and_then(|cached| cached.last_modified.as_deref()) {
+            request = request.header(header::IF_MODIFIED_SINCE, last_modified);
+        }
+        request
+    };
+
+    let response = config
+        .retry
+        .send(build_request)
+        .await
+        .map_err(UpdaterError::network("API request failed"))?;
+
+    let remaining = header_u64(&response, "x-ratelimit-remaining");
+    let reset_at = header_u64(&response, "x-ratelimit-reset")
+        .map(|reset| UNIX_EPOCH + Duration::from_secs(reset));
+
+    let status = response.status();
+    if status == StatusCode::NOT_MODIFIED && cached.is_some() {
+        return Ok(None);
+    }
+
+    if status == StatusCode::TOO_MANY_REQUESTS
+        || (status == StatusCode::FORBIDDEN && remaining == Some(0))
+    {
+        return Err(UpdaterError::RateLimited { reset_at });
+    }
+
+    let response = check_status(response).await?;
+
+    if let Some(remaining) = remaining.filter(|remaining| *remaining <= RATE_LIMIT_WARNING) {
+        say!(
+            "{} {} requests left",
+            style("GitHub API rate limit almost reached:").yellow(),
+            remaining
+        );
+    }
+
+    let etag = header_str(&response, header::ETAG.as_str());
+    let last_modified = header_str(&response, header::LAST_MODIFIED.as_str());
+    let body = response
+        .text()
+        .await
+        .map_err(UpdaterError::network("Failed to read API response"))?;
+
+    Ok(Some(CachedResponse {
+        body,
+        etag,
+        last_modified,
+        checked_at: unix_now(),
+    }))
+}
+
+/// Turns an error status into [`UpdaterError::HttpStatus`], keeping the
+/// response body for the error message.
+pub async fn check_status(response: Response) -> Result<Response, UpdaterError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    Err(UpdaterError::HttpStatus {
+        code: status.as_u16(),
+        body: response.text().await.unwrap_or_default(),
+    })
+}
+
+fn header_str(response: &Response, name: &str) -> Option<String> {
+    response
+        .headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+fn header_u64(response: &Response, name: &str) -> Option<u64> {
+    header_str(response, name).and_then(|value| value.parse().ok())
+}
+
+/// Checksum files, patches and other metadata published next to the real
+/// builds.
+fn is_sidecar(asset: &Asset) -> bool {
+    asset.name.ends_with(CHECKSUM_SUFFIX)
+        || delta::is_patch(&asset.name)
+        || SIGNATURE_SUFFIXES
+            .iter()
+            .any(|suffix| asset.name.ends_with(suffix))
+        || asset
+            .content_type
+            .as_deref()
+            .is_some_and(|content_type| content_type.starts_with("text/"))
+}
+
+/// Picks the asset to download: the first one matching the configured name
+/// pattern, or otherwise the best match for the current OS and architecture.
+pub fn select_asset<'a>(
+    release: &'a Release,
+    pattern: Option<&Pattern>,
+) -> Result<&'a Asset, UpdaterError> {
+    let candidates = builds(release);
+
+    let selected = match pattern {
+        Some(pattern) => select_by_pattern(&candidates, pattern),
+        None => select_for_platform(&candidates),
+    };
+
+    selected.ok_or_else(|| UpdaterError::NoMatchingAsset {
+        pattern: pattern.map(|pattern| pattern.as_str().to_string()),
+        candidates: candidates.iter().map(|asset| asset.name.clone()).collect(),
+    })
+}
+
+/// Picks the asset for the current platform among those that run as they
+/// are, for replacing a running executable with.
+pub fn select_executable(release: &Release) -> Result<&Asset, UpdaterError> {
+    let candidates: Vec<&Asset> = builds(release)
+        .into_iter()
+        .filter(|asset| !archive::is_archive(Path::new(&asset.name)))
+        .collect();
+
+    select_for_platform(&candidates).ok_or_else(|| UpdaterError::NoMatchingAsset {
+        pattern: None,
+        candidates: candidates.iter().map(|asset| asset.name.clone()).collect(),
+    })
+}
+
+fn builds(release: &Release) -> Vec<&Asset> {
+    release
+        .assets
+        .iter()
+        .filter(|asset| !is_sidecar(asset))
+        .collect()
+}
+
+fn select_by_pattern<'a>(candidates: &[&'a Asset], pattern: &Pattern) -> Option<&'a Asset> {
+    let options = MatchOptions {
+        case_sensitive: false,
+        ..MatchOptions::new()
+    };
+
+    candidates
+        .iter()
+        .copied()
+        .find(|asset| pattern.matches_with(&asset.name, options))
+}
+
+fn select_for_platform<'a>(candidates: &[&'a Asset]) -> Option<&'a Asset> {
+    let for_os: Vec<&Asset> = candidates
+        .iter()
+        .copied()
+        .filter(|asset| platform::matches_os(&asset.name))
+        .collect();
+
+    // Prefer a build for our CPU, then one that doesn't name a CPU at all,
+    // and among those the format that is easiest to run here
+    let best = |matches: &dyn Fn(&Asset) -> bool| {
+        for_os
+            .iter()
+            .copied()
+            .filter(|asset| matches(asset))
+            .min_by_key(|asset| platform::format_rank(&asset.name))
+    };
+    best(&|asset| platform::matches_arch(&asset.name))
+        .or_else(|| best(&|asset| !platform::mentions_any_arch(&asset.name)))
+}
+
+/// Looks up the expected SHA-256 of `asset`, either from the digest GitHub
+/// reports for it or from a `<name>.sha256` sidecar asset in the same release.
+pub async fn get_expected_checksum(
+    client: &Client,
+    config: &Config,
+    release: &Release,
+    asset: &Asset,
+) -> Result<Option<String>, UpdaterError> {
+    if let Some(digest) = asset
+        .digest
+        .as_deref()
+        .and_then(|digest| digest.strip_prefix("sha256:"))
+    {
+        return Ok(Some(digest.to_lowercase()));
+    }
+
+    let sidecar_name = format!("{}{}", asset.name, CHECKSUM_SUFFIX);
+    let Some(sidecar) = release.assets.iter().find(|a| a.name == sidecar_name) else {
+        say!(
+            "{} {}",
+            style("No checksum published, skipping verification for:").yellow(),
+            asset.name
+        );
+        return Ok(None);
+    };
+
+    let sidecar_url = config.asset_url(&sidecar.browser_download_url)?;
+    let response = config
+        .retry
+        .send(|| client.get(&sidecar_url))
+        .await
+        .map_err(UpdaterError::network(format!(
+            "Failed to fetch {}",
+            sidecar_name
+        )))?;
+    let body = check_status(response)
+        .await?
+        .text()
+        .await
+        .map_err(UpdaterError::network(format!(
+            "Failed to read {}",
+            sidecar_name
+        )))?;
+
+    // Sidecars are usually in `sha256sum` format: "<hex>  <filename>"
+    match body.split_whitespace().next() {
+        Some(hash) if hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()) => {
+            Ok(Some(hash.to_lowercase()))
+        }
+        _ => Err(UpdaterError::InvalidResponse(format!(
+            "Invalid checksum file: {}",
+            sidecar_name
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn release(names: &[&str]) -> Release {
+        Release {
+            tag_name: "v1.0".to_string(),
+            assets: names
+                .iter()
+                .map(|name| Asset {
+                    name: name.to_string(),
+                    browser_download_url: format!("https://example.com/{}", name),
+                    size: 1,
+                    content_type: None,
+                    digest: None,
+                })
+                .collect(),
+            prerelease: false,
+            published_at: None,
+        }
+    }
+
+    fn selected(names: &[&str], pattern: Option<&str>) -> Option<String> {
+        let pattern = pattern.map(|pattern| Pattern::new(pattern).unwrap());
+        select_asset(&release(names), pattern.as_ref())
+            .ok()
+            .map(|asset| asset.name.clone())
+    }
+
+    #[test]
+    fn pattern_picks_the_first_match_ignoring_case() {
+        let names = ["CollapseLoader-linux.tar.gz", "collapseloader-1.0.EXE"];
+        assert_eq!(
+            selected(&names, Some("CollapseLoader*.exe")).as_deref(),
+            Some("collapseloader-1.0.EXE")
+        );
+    }
+
+    #[test]
+    fn sidecars_are_never_picked() {
+        let names = [
+            "CollapseLoader.exe.sha256",
+            "CollapseLoader.exe.minisig",
+            "CollapseLoader.exe.from-v0.9.zst",
+        ];
+        assert_eq!(selected(&names, Some("CollapseLoader*")), None);
+    }
+
+    #[test]
+    fn no_match_lists_the_candidates() {
+        let names = ["CollapseLoader.exe", "CollapseLoader.exe.sha256"];
+        let pattern = Pattern::new("*.dmg").unwrap();
+        match select_asset(&release(&names), Some(&pattern)) {
+            Err(UpdaterError::NoMatchingAsset {
+                pattern,
+                candidates,
+            }) => {
+                assert_eq!(pattern.as_deref(), Some("*.dmg"));
+                assert_eq!(candidates, ["CollapseLoader.exe"]);
+            }
+            _ => panic!("expected NoMatchingAsset"),
+        }
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn platform_prefers_our_arch_then_the_best_format() {
+        let names = [
+            "CollapseLoader-windows-x64.exe",
+            "CollapseLoader-linux-aarch64.AppImage",
+            "CollapseLoader-linux-x86_64.tar.gz",
+            "CollapseLoader-linux-x86_64.AppImage",
+            "CollapseLoader-linux.AppImage",
+        ];
+        assert_eq!(
+            selected(&names, None).as_deref(),
+            Some("CollapseLoader-linux-x86_64.AppImage")
+        );
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn executables_skip_archives() {
+        let names = [
+            "CollapseUpdater-linux-x86_64.zip",
+            "CollapseUpdater-linux-x86_64.tar.gz",
+            "CollapseUpdater-linux",
+        ];
+        let with_binary = release(&names);
+        assert_eq!(
+            select_executable(&with_binary)
+                .ok()
+                .map(|asset| asset.name.as_str()),
+            Some("CollapseUpdater-linux")
+        );
+        assert!(select_executable(&release(&names[..2])).is_err());
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn platform_falls_back_to_arch_neutral_builds() {
+        let names = [
+            "CollapseLoader-linux-aarch64.AppImage",
+            "CollapseLoader-linux.tar.gz",
+        ];
+        assert_eq!(
+            selected(&names, None).as_deref(),
+            Some("CollapseLoader-linux.tar.gz")
+        );
+        assert_eq!(selected(&names[..1], None), None);
+    }
+}
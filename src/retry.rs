@@ -0,0 +1,129 @@
+use std::time::Duration;
+
+use console::style;
+use reqwest::{RequestBuilder, Response, StatusCode};
+
+/// How often and how patiently failed requests are retried.
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one.
+    pub attempts: u32,
+    /// Upper bound for the delay between two attempts.
+    pub max_delay: Duration,
+}
+
+const BASE_DELAY: Duration = Duration::from_millis(500);
+
+impl RetryPolicy {
+    /// Exponential backoff capped at `max_delay`, with jitter so that many
+    /// clients behind the same NAT don't retry in lockstep.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponential = BASE_DELAY.saturating_mul(2u32.saturating_pow(attempt));
+        exponential
+            .min(self.max_delay)
+            .mul_f64(0.5 + fastrand::f64() / 2.0)
+    }
+
+    /// Whether another attempt may follow attempt number `attempt` (0-based).
+    pub fn should_retry(&self, attempt: u32) -> bool {
+        attempt + 1 < self.attempts
+    }
+
+    /// Logs the failure and waits before attempt `attempt + 1`.
+    pub async fn wait(&self, attempt: u32, reason: &dyn std::fmt::Display) {
+        let delay = self.delay(attempt);
+        eprintln!(
+            "{} {} (retrying in {:.1}s, attempt {}/{})",
+            style("Request failed:").yellow(),
+            reason,
+            delay.as_secs_f32(),
+            attempt + 2,
+            self.attempts
+        );
+        tokio::time::sleep(delay).await;
+    }
+
+    /// Sends the request built by `request`, retrying connection problems
+    /// and 5xx responses.
+    pub async fn send(
+        &self,
+        request: impl Fn() -> RequestBuilder,
+    ) -> Result<Response, reqwest::Error> {
+        let mut attempt = 0;
+        loop {
+            let result = request().send().await;
+            let reason = match &result {
+                Ok(response) if is_transient_status(response.status()) => {
+                    response.status().to_string()
+                }
+                Err(err) if is_transient_error(err) => err.to_string(),
+                _ => return result,
+            };
+
+            if !self.should_retry(attempt) {
+                return result;
+            }
+            self.wait(attempt, &reason).await;
+            attempt += 1;
+        }
+    }
+}
+
+pub fn is_transient_status(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::REQUEST_TIMEOUT
+}
+
+/// Connection failures, timeouts and streams that broke off halfway.
+pub fn is_transient_error(err: &reqwest::Error) -> bool {
+    err.is_connect() || err.is_timeout() || err.is_request() || err.is_body() || err.is_decode()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(attempts: u32, max_delay: Duration) -> RetryPolicy {
+        RetryPolicy {
+            attempts,
+            max_delay,
+        }
+    }
+
+    #[test]
+    fn delay_doubles_with_jitter_up_to_the_cap() {
+        let retry = policy(10, Duration::from_secs(3));
+        for (attempt, full) in [(0, 500), (1, 1000), (2, 2000), (3, 3000), (8, 3000)] {
+            let full = Duration::from_millis(full);
+            let delay = retry.delay(attempt);
+            assert!(
+                delay >= full / 2 && delay <= full,
+                "attempt {}: {:?}",
+                attempt,
+                delay
+            );
+        }
+    }
+
+    #[test]
+    fn delay_survives_huge_attempt_numbers() {
+        let retry = policy(u32::MAX, Duration::from_secs(30));
+        assert!(retry.delay(u32::MAX - 1) <= Duration::from_secs(30));
+    }
+
+    #[test]
+    fn attempts_include_the_first_one() {
+        let retry = policy(3, Duration::ZERO);
+        assert!(retry.should_retry(0));
+        assert!(retry.should_retry(1));
+        assert!(!retry.should_retry(2));
+        assert!(!policy(1, Duration::ZERO).should_retry(0));
+    }
+
+    #[test]
+    fn only_server_errors_and_timeouts_are_transient() {
+        assert!(is_transient_status(StatusCode::INTERNAL_SERVER_ERROR));
+        assert!(is_transient_status(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(is_transient_status(StatusCode::REQUEST_TIMEOUT));
+        assert!(!is_transient_status(StatusCode::NOT_FOUND));
+        assert!(!is_transient_status(StatusCode::FORBIDDEN));
+    }
+}
@@ -0,0 +1,159 @@
+use std::{env, fs, path::Path, process};
+
+use console::style;
+use reqwest::Client;
+use tokio::process::Command;
+
+use crate::{
+    config::Config,
+    download,
+    install::with_suffix,
+    launcher,
+    output::{self, say, Event},
+    release::{get_expected_checksum, get_updater_release, select_executable},
+    signature, version, UpdaterError,
+};
+
+/// Set on the relaunched updater so it doesn't try to update itself again.
+pub const RELAUNCHED_ENV: &str = "COLLAPSE_UPDATER_RELAUNCHED";
+
+/// Checks the updater's own repository for a newer release. If there is
+/// one, it replaces the running executable and reruns the current
+/// invocation with the new binary, exiting with its exit code. Returns
+/// normally when there is nothing to do.
+pub async fn run(client: &Client, config: &Config) -> Result<(), UpdaterError> {
+    if !config.self_update || config.offline || env::var_os(RELAUNCHED_ENV).is_some() {
+        return Ok(());
+    }
+
+    let exe = env::current_exe().map_err(UpdaterError::io("Failed to locate the updater"))?;
+
+    let release = get_updater_release(client, config).await?;
+    let current = version::parse(env!("CARGO_PKG_VERSION"));
+    match (version::parse(&release.tag_name), current) {
+        (Some(remote), Some(current)) if remote > current => {}
+        _ => return Ok(()),
+    }
+
+    let asset = select_executable(&release)?;
+    say!(
+        "{} {} -> {}",
+        style("Updating the updater:").blue().bold(),
+        env!("CARGO_PKG_VERSION"),
+        release.tag_name
+    );
+
+    let new_exe = with_suffix(&exe, ".new");
+    let expected_sha256 = get_expected_checksum(client, config, &release, asset).await?;
+    download::download(
+        client,
+        &config.asset_url(&asset.browser_download_url)?,
+        &new_exe,
+        asset.size,
+        expected_sha256.as_deref(),
+        &config.retry,
+    )
+    .await?;
+    output::emit(Event::Downloaded {
+        file: &new_exe,
+        source: &asset.browser_download_url,
+    });
+
+    signature::fetch_signature(client, config, &release, asset, &new_exe).await?;
+    let verified = signature::verify_strict(&new_exe);
+    let _ = fs::remove_file(signature::signature_path(&new_exe));
+    if let Err(err) = verified {
+        let _ = fs::remove_file(&new_exe);
+        return Err(err);
+    }
+
+    swap(&exe, &new_exe)?;
+    say!(
+        "{} {}",
+        style("Updater updated, restarting:").green().bold(),
+        release.tag_name
+    );
+
+    let mut child = match Command::new(&exe)
+        .args(env::args_os().skip(1))
+        .env(RELAUNCHED_ENV, "1")
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(err) => {
+            // Whatever was installed doesn't run, so go back to what does
+            restore(&exe);
+            return Err(UpdaterError::io("Failed to restart the updater")(err));
+        }
+    };
+    let status = launcher::wait_forwarding_signals(&mut child)
+        .await
+        .map_err(UpdaterError::io("Failed to wait for the new updater"))?;
+
+    process::exit(launcher::exit_code(status).unwrap_or(1));
+}
+
+/// Moves the running executable out of the way and the new one into its
+/// place. Windows won't delete a running executable but does allow
+/// renaming it; the old copy is removed by the next run.
+fn swap(exe: &Path, new_exe: &Path) -> Result<(), UpdaterError> {
+    let old_exe = with_suffix(exe, ".old");
+
+    if let Ok(metadata) = fs::metadata(exe) {
+        let _ = fs::set_permissions(new_exe, metadata.permissions());
+    }
+
+    let _ = fs::remove_file(&old_exe);
+    fs::rename(exe, &old_exe).map_err(UpdaterError::io("Failed to move the old updater aside"))?;
+
+    if let Err(err) = fs::rename(new_exe, exe) {
+        // Put the old updater back so there is still something to run
+        let _ = fs::rename(&old_exe, exe);
+        return Err(UpdaterError::io("Failed to install the new updater")(err));
+    }
+
+    Ok(())
+}
+
+/// Undoes [`swap`], putting the running executable back in its place.
+fn restore(exe: &Path) {
+    let old_exe = with_suffix(exe, ".old");
+    let _ = fs::remove_file(exe);
+    if let Err(err) = fs::rename(&old_exe, exe) {
+        eprintln!(
+            "{} {}: {}",
+            style("Failed to restore").red(),
+            exe.display(),
+            err
+        );
+    }
+}
+
+/// Removes what a previous self-update left behind: the replaced
+/// executable and an unfinished download of the new one. Nothing is touched
+/// in a relaunched updater, whose parent still runs from the old copy.
+pub fn delete_stale() {
+    if env::var_os(RELAUNCHED_ENV).is_some() {
+        return;
+    }
+    let Ok(exe) = env::current_exe() else {
+        return;
+    };
+
+    for stale in [
+        with_suffix(&exe, ".old"),
+        download::part_path(&with_suffix(&exe, ".new")),
+    ] {
+        if !stale.exists() {
+            continue;
+        }
+        if let Err(err) = fs::remove_file(&stale) {
+            eprintln!(
+                "{} {}: {}",
+                style("Failed to delete").red(),
+                stale.display(),
+                err
+            );
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use console::style;
+use minisign_verify::{PublicKey, Signature};
+use reqwest::Client;
+
+use crate::{
+    config::Config,
+    install::with_suffix,
+    output::{self, Event},
+    release::{check_status, Asset, Release},
+    UpdaterError,
+};
+
+/// Names a detached signature may have next to the asset it signs. `.sig`
+/// is also used by other tools, so it only counts if it is minisign's.
+pub const SIGNATURE_SUFFIXES: &[&str] = &[".minisig", ".sig"];
+
+/// Where the signature of a downloaded build is kept, so it can be checked
+/// again before every launch.
+pub fn signature_path(file: &Path) -> PathBuf {
+    with_suffix(file, ".minisig")
+}
+
+/// Minisign public keys baked in at build time from the
+/// `COLLAPSE_UPDATER_PUBLIC_KEYS` environment variable, separated by commas
+/// or whitespace. A build without keys doesn't check signatures.
+fn trusted_keys() -> Vec<PublicKey> {
+    option_env!("COLLAPSE_UPDATER_PUBLIC_KEYS")
+        .unwrap_or_default()
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|key| !key.is_empty())
+        .map(|key| PublicKey::from_base64(key).expect("keys are checked by build.rs"))
+        .collect()
+}
+
+/// Downloads the detached signature of `asset`, if the release has one, to
+/// [`signature_path`] of `file`.
+pub async fn fetch_signature(
+    client: &Client,
+    config: &Config,
+    release: &Release,
+    asset: &Asset,
+    file: &Path,
+) -> Result<(), UpdaterError> {
+    let Some(sig_asset) = SIGNATURE_SUFFIXES.iter().find_map(|suffix| {
+        let name = format!("{}{}", asset.name, suffix);
+        release.assets.iter().find(|a| a.name == name)
+    }) else {
+        return Ok(());
+    };
+
+    let sig_url = config.asset_url(&sig_asset.browser_download_url)?;
+    let response =
+        config
+            .retry
+            .send(|| client.get(&sig_url))
+            .await
+            .map_err(UpdaterError::network(format!(
+                "Failed to fetch {}",
+                sig_asset.name
+            )))?;
+    let body = check_status(response)
+        .await?
+        .text()
+        .await
+        .map_err(UpdaterError::network(format!(
+            "Failed to read {}",
+            sig_asset.name
+        )))?;
+
+    if sig_asset.name.ends_with(".sig") && Signature::decode(&body).is_err() {
+        eprintln!(
+            "{} {}",
+            style("Ignoring signature that isn't minisign's:").yellow(),
+            sig_asset.name
+        );
+        let _ = fs::remove_file(signature_path(file));
+        return Ok(());
+    }
+
+    fs::write(signature_path(file), body).map_err(UpdaterError::io("Failed to save signature"))
+}
+
+/// Checks `file` against its saved signature with the trusted keys.
+/// Unsigned or badly signed files are refused unless `--allow-unsigned`.
+pub fn verify(file: &Path, config: &Config) -> Result<(), UpdaterError> {
+    verify_with(file, config.allow_unsigned)
+}
+
+/// Like [`verify`], but `--allow-unsigned` doesn't apply: used for the
+/// updater's own executable, which replaces the one that checks signatures.
+pub fn verify_strict(file: &Path) -> Result<(), UpdaterError> {
+    verify_with(file, false)
+}
+
+fn verify_with(file: &Path, allow_unsigned: bool) -> Result<(), UpdaterError> {
+    let keys = trusted_keys();
+    if keys.is_empty() {
+        return Ok(());
+    }
+
+    let result = check(file, &keys);
+    output::emit(Event::Signature {
+        file,
+        ok: result.is_ok(),
+    });
+
+    match result {
+        Ok(()) => Ok(()),
+        Err(reason) if allow_unsigned => {
+            eprintln!(
+                "{} {}: {}",
+                style("Launching without a valid signature").yellow(),
+                file.display(),
+                reason
+            );
+            Ok(())
+        }
+        Err(reason) => Err(UpdaterError::SignatureInvalid(format!(
+            "{}: {}",
+            file.display(),
+            reason
+        ))),
+    }
+}
+
+fn check(file: &Path, keys: &[PublicKey]) -> Result<(), String> {
+    let signature = fs::read_to_string(signature_path(file))
+        .map_err(|_| "no signature published".to_string())?;
+    let signature = Signature::decode(&signature).map_err(|err| err.to_string())?;
+    let data = fs::read(file).map_err(|err| err.to_string())?;
+
+    if keys
+        .iter()
+        .any(|key| key.verify(&data, &signature, false).is_ok())
+    {
+        Ok(())
+    } else {
+        Err("signature doesn't match any trusted key".to_string())
+    }
+}
@@ -0,0 +1,80 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+pub const STATE_FILE_NAME: &str = "collapse_updater.state.json";
+
+/// What the updater remembers between runs, stored in the install root.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct State {
+    /// Loader executables that have been installed, relative to the install
+    /// root, most recent first.
+    pub history: Vec<PathBuf>,
+    /// Release tag of the most recently installed build.
+    pub installed_version: Option<String>,
+    #[serde(skip)]
+    root: PathBuf,
+}
+
+impl State {
+    /// Loads the state file from `root`, treating a missing or unreadable
+    /// one as empty.
+    pub fn load(root: &Path) -> Self {
+        let state: Self = fs::read_to_string(root.join(STATE_FILE_NAME))
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default();
+        State {
+            root: root.to_path_buf(),
+            ..state
+        }
+    }
+
+    pub fn save(&self) -> Result<(), io::Error> {
+        let contents = serde_json::to_string_pretty(self).map_err(io::Error::other)?;
+        fs::write(self.root.join(STATE_FILE_NAME), contents)
+    }
+
+    /// Moves `build` to the front of the install history.
+    pub fn record_install(&mut self, build: &Path, tag: &str) {
+        let entry = build
+            .strip_prefix(&self.root)
+            .unwrap_or(build)
+            .to_path_buf();
+        self.history.retain(|existing| *existing != entry);
+        self.history.insert(0, entry);
+        self.installed_version = Some(tag.to_string());
+    }
+
+    /// Updates the install history after `from` was moved to `to`.
+    pub fn record_move(&mut self, from: &Path, to: &Path) {
+        let relative = |path: &Path| path.strip_prefix(&self.root).unwrap_or(path).to_path_buf();
+        let (from, to) = (relative(from), relative(to));
+        for entry in &mut self.history {
+            if *entry == from {
+                *entry = to.clone();
+            }
+        }
+    }
+
+    /// Full paths of the installed builds, most recent first.
+    pub fn builds(&self) -> impl Iterator<Item = PathBuf> + '_ {
+        self.history.iter().map(|entry| self.root.join(entry))
+    }
+
+    /// The most recently installed build, if it is still on disk.
+    pub fn current_install(&self) -> Option<PathBuf> {
+        self.builds().next().filter(|build| build.is_file())
+    }
+
+    /// The build that was installed before `current`, if it is still on disk.
+    pub fn previous_install(&self, current: Option<&Path>) -> Option<PathBuf> {
+        self.builds()
+            .filter(|build| Some(build.as_path()) != current)
+            .find(|build| build.is_file())
+    }
+}
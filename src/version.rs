@@ -0,0 +1,54 @@
+use semver::Version;
+
+/// Parses a release tag such as `v1.4.2` or `1.4` as a semantic version,
+/// filling in missing minor/patch numbers with zeros.
+pub fn parse(tag: &str) -> Option<Version> {
+    let tag = tag.trim().trim_start_matches(['v', 'V']);
+    if let Ok(version) = Version::parse(tag) {
+        return Some(version);
+    }
+
+    // Split off any pre-release/build suffix before padding the core part
+    let split = tag.find(['-', '+']).unwrap_or(tag.len());
+    let (core, suffix) = tag.split_at(split);
+    let parts = core.split('.').count();
+    if parts == 0 || parts > 3 {
+        return None;
+    }
+
+    let padded = format!("{}{}{}", core, ".0".repeat(3 - parts), suffix);
+    Version::parse(&padded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pads_short_versions() {
+        assert_eq!(parse("v1.4"), Some(Version::new(1, 4, 0)));
+        assert_eq!(parse("2"), Some(Version::new(2, 0, 0)));
+        assert_eq!(parse(" V1.4.2 "), Some(Version::new(1, 4, 2)));
+    }
+
+    #[test]
+    fn keeps_pre_release_and_build_suffixes() {
+        assert_eq!(parse("v1.4-beta.1"), Version::parse("1.4.0-beta.1").ok());
+        assert_eq!(parse("1.4+build.7"), Version::parse("1.4.0+build.7").ok());
+    }
+
+    #[test]
+    fn orders_pre_releases_before_the_release() {
+        let beta = parse("v1.1-beta").unwrap();
+        assert!(parse("v1.0").unwrap() < beta);
+        assert!(beta < parse("v1.1").unwrap());
+        assert!(parse("v1.1-alpha").unwrap() < beta);
+    }
+
+    #[test]
+    fn rejects_non_versions() {
+        assert_eq!(parse("latest"), None);
+        assert_eq!(parse(""), None);
+        assert_eq!(parse("1.2.3.4"), None);
+    }
+}